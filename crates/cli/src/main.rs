@@ -1,15 +1,28 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{
+	builder::{OsStringValueParser, TypedValueParser},
+	Args, Parser, Subcommand,
+};
+use console::Term;
 use futures_util::StreamExt;
-use orbit_client::Client;
-use orbit_types::{Log, Progress, Stage};
-use pin_utils::pin_mut;
+use orbit_client::{Artifact, Build, Client};
+use orbit_types::{
+	DownloadProgress, EnvResponse, EnvUpdate, Log, Progress, Release, SiteStatus, SiteSummary,
+	Stage,
+};
+use std::{
+	fmt::Write,
+	fs,
+	path::{Path, PathBuf},
+};
 use url::Url;
 
 mod utils;
 
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
 #[derive(Debug, Parser)]
 #[clap(
 	name = "orbit",
@@ -22,9 +35,29 @@ struct Cli {
 	#[arg(short, long, env = "ORBIT_URL")]
 	url: Url,
 
-	/// Orbit authenticaton token.
-	#[arg(short, long, env = "ORBIT_TOKEN")]
-	token: String,
+	/// Orbit authenticaton token. If not provided, an OIDC token is requested from GitHub Actions.
+	#[arg(short, long, env = "ORBIT_TOKEN", hide_env_values = true)]
+	token: Option<String>,
+
+	/// Key to sign requests with, if the Orbit instance requires signed requests.
+	#[arg(long, env = "ORBIT_SIGNING_KEY", hide_env_values = true)]
+	signing_key: Option<String>,
+
+	/// Client certificate to present, if the Orbit instance requires mutual TLS.
+	#[arg(long, env = "ORBIT_CLIENT_CERT", requires = "client_key")]
+	client_cert: Option<PathBuf>,
+
+	/// Private key for the client certificate, in PKCS#8 PEM format.
+	#[arg(long, env = "ORBIT_CLIENT_KEY", requires = "client_cert")]
+	client_key: Option<PathBuf>,
+
+	/// PEM bundle of extra CA certificates to trust, for instances using a private CA.
+	#[arg(long, env = "ORBIT_CA_CERT")]
+	ca_cert: Option<PathBuf>,
+
+	/// The audience to request GitHub Actions OIDC tokens for.
+	#[arg(long, env = "ORBIT_OIDC_AUDIENCE", default_value = "orbit")]
+	oidc_audience: String,
 
 	/// Enable debug mode
 	#[clap(short = 'D', long)]
@@ -44,7 +77,141 @@ enum Commands {
 		/// The git ref to deploy. If not provided, the default branch will be used.
 		#[arg(long, env = "DEPLOY_REF")]
 		r#ref: Option<String>,
+
+		#[clap(flatten)]
+		build: DeployBuild,
+	},
+
+	/// List the Orbit sites the token can access.
+	Sites {
+		/// Print the sites as JSON instead of a table.
+		#[arg(long)]
+		json: bool,
+	},
+
+	/// Show what's deployed on an Orbit site.
+	Status {
+		/// The name of the site.
+		slug: String,
+	},
+
+	/// Manage the `.env` file of an Orbit site.
+	Env {
+		#[clap(subcommand)]
+		command: EnvCommands,
+	},
+}
+
+#[derive(Debug, Subcommand)]
+enum EnvCommands {
+	/// List the variables in the site's `.env` file. Values are always masked.
+	Get {
+		/// The name of the site.
+		slug: String,
+
+		/// Only show this variable.
+		key: Option<String>,
 	},
+
+	/// Add or overwrite variables in the site's `.env` file.
+	Set {
+		/// The name of the site.
+		slug: String,
+
+		/// The variables to set, as KEY=VALUE pairs.
+		#[arg(required = true, value_parser = parse_var)]
+		vars: Vec<(String, String)>,
+
+		#[clap(flatten)]
+		after: AfterEnvChange,
+	},
+
+	/// Remove variables from the site's `.env` file.
+	Unset {
+		/// The name of the site.
+		slug: String,
+
+		/// The names of the variables to remove.
+		#[arg(required = true)]
+		keys: Vec<String>,
+
+		#[clap(flatten)]
+		after: AfterEnvChange,
+	},
+}
+
+// options can be set to empty values by the GitHub Action, so they're checked in `DeployBuild::validate`
+#[derive(Debug, Default, Args)]
+struct DeployBuild {
+	/// Upload a build (as a .tar.gz or .zip) and deploy it as is, instead of the repository.
+	/// The ref is only recorded, to show what the build was made from.
+	#[arg(long, value_name = "PATH", env = "DEPLOY_ARTIFACT", value_parser = OsStringValueParser::new().map(PathBuf::from))]
+	artifact: Option<PathBuf>,
+
+	/// Deploy the asset with this name from the release tagged with the ref (or the latest release).
+	#[arg(long, value_name = "NAME", env = "DEPLOY_RELEASE_ASSET")]
+	release_asset: Option<String>,
+
+	/// Deploy the artifact with this name from a GitHub Actions workflow run.
+	#[arg(long, value_name = "NAME", env = "DEPLOY_WORKFLOW_ARTIFACT")]
+	workflow_artifact: Option<String>,
+
+	/// The workflow run to take the artifact from. Defaults to the latest one that built the ref.
+	#[arg(long, value_name = "ID")]
+	run_id: Option<u64>,
+
+	/// The SHA-256 checksum the release asset or workflow artifact must have, to deploy exactly the same build as elsewhere.
+	#[arg(long, value_name = "SHA256", env = "DEPLOY_CHECKSUM")]
+	checksum: Option<String>,
+}
+
+impl DeployBuild {
+	/// Ignore options set to empty values, and check the rest can be combined.
+	fn validate(self) -> Result<Self> {
+		let build = Self {
+			artifact: self.artifact.filter(|path| !path.as_os_str().is_empty()),
+			release_asset: self.release_asset.filter(|name| !name.is_empty()),
+			workflow_artifact: self.workflow_artifact.filter(|name| !name.is_empty()),
+			run_id: self.run_id,
+			checksum: self.checksum.filter(|checksum| !checksum.is_empty()),
+		};
+
+		if build.release_asset.is_some() && build.workflow_artifact.is_some() {
+			bail!("Pick either a release asset or a workflow artifact to deploy, not both");
+		}
+		if build.artifact.is_some() && build.uses_github() {
+			bail!("Uploaded artifacts can't be combined with builds published on GitHub");
+		}
+
+		Ok(build)
+	}
+
+	/// Whether a build published on GitHub was asked for.
+	const fn uses_github(&self) -> bool {
+		self.release_asset.is_some()
+			|| self.workflow_artifact.is_some()
+			|| self.run_id.is_some()
+			|| self.checksum.is_some()
+	}
+
+	/// The build published on GitHub to deploy, if one was picked.
+	fn github_build(&self) -> Option<Build> {
+		self.release_asset
+			.clone()
+			.map(Build::ReleaseAsset)
+			.or_else(|| self.workflow_artifact.clone().map(Build::WorkflowArtifact))
+	}
+}
+
+#[derive(Debug, Args)]
+struct AfterEnvChange {
+	/// Trigger a new deployment after applying the changes.
+	#[arg(long, conflicts_with = "config_cache")]
+	redeploy: bool,
+
+	/// Run `php artisan config:cache` on the live deployment after applying the changes.
+	#[arg(long)]
+	config_cache: bool,
 }
 
 #[tokio::main]
@@ -55,7 +222,27 @@ async fn main() -> Result<()> {
 	utils::set_hook();
 	utils::logs(cli.debug);
 
-	let client = Client::new(cli.url, cli.token);
+	let token = match cli.token.filter(|token| !token.is_empty()) {
+		Some(token) => token,
+		None => match orbit_client::github_actions_token(&cli.oidc_audience).await {
+			Ok(token) => token,
+			Err(error) => {
+				log::error!("No token provided. {error}");
+				std::process::exit(1);
+			},
+		},
+	};
+
+	let mut client = Client::new(cli.url, token);
+	if let Some(key) = cli.signing_key.filter(|key| !key.is_empty()) {
+		client = client.with_signing_key(key);
+	}
+	if let Some(ca_cert) = cli.ca_cert {
+		client = client.with_root_certificates(&read(&ca_cert)?)?;
+	}
+	if let (Some(cert), Some(key)) = (cli.client_cert, cli.client_key) {
+		client = client.with_identity(&read(&cert)?, &read(&key)?)?;
+	}
 
 	if let Err(error) = handle_command(cli.command, &client).await {
 		log::error!("{error}");
@@ -70,28 +257,342 @@ async fn main() -> Result<()> {
 
 async fn handle_command(commands: Commands, client: &Client) -> Result<()> {
 	match commands {
-		Commands::Deploy { slug, r#ref } => {
-			run_deploy(slug, r#ref.filter(|s| !s.is_empty()), client).await
+		Commands::Deploy { slug, r#ref, build } => {
+			run_deploy(slug, r#ref.filter(|s| !s.is_empty()), build, client).await
+		},
+		Commands::Sites { json } => {
+			let sites = client.sites().await?;
+
+			if json {
+				println!("{}", serde_json::to_string_pretty(&sites)?);
+			} else {
+				print_sites(&sites);
+			}
+
+			Ok(())
+		},
+		Commands::Status { slug } => {
+			print_status(&client.site_status(&slug).await?);
+			Ok(())
 		},
+		Commands::Env { command } => run_env(command, client).await,
 	}
 }
 
-async fn run_deploy(slug: String, r#ref: Option<String>, client: &Client) -> Result<()> {
-	let stream = client.deploy(&slug, r#ref.as_deref());
-	pin_mut!(stream);
+async fn run_env(command: EnvCommands, client: &Client) -> Result<()> {
+	let (slug, update, after) = match command {
+		EnvCommands::Get { slug, key } => {
+			let response = client.env(&slug).await?;
+
+			if let Some(key) = key {
+				let Some(value) = response.env.get(&key) else {
+					bail!("{key} is not set");
+				};
+
+				println!("{key}={value}");
+			} else {
+				for (key, value) in response.env {
+					println!("{key}={value}");
+				}
+			}
+
+			return Ok(());
+		},
+		EnvCommands::Set { slug, vars, after } => (
+			slug,
+			EnvUpdate {
+				set: vars.into_iter().collect(),
+				..EnvUpdate::default()
+			},
+			after,
+		),
+		EnvCommands::Unset { slug, keys, after } => (
+			slug,
+			EnvUpdate {
+				unset: keys,
+				..EnvUpdate::default()
+			},
+			after,
+		),
+	};
+
+	let EnvResponse { config_cache, .. } = client
+		.update_env(&slug, &update, after.config_cache)
+		.await?;
+	log::info!("Updated environment");
+
+	if let Some(output) = config_cache {
+		for log in output.logs {
+			print_log(log);
+		}
+
+		if !output.success {
+			bail!("Failed to cache the configuration");
+		}
+
+		log::info!("Cached configuration");
+	}
+
+	if after.redeploy {
+		run_deploy(slug, None, DeployBuild::default(), client).await?;
+	}
+
+	Ok(())
+}
+
+fn print_sites(sites: &[SiteSummary]) {
+	let rows = sites
+		.iter()
+		.map(|site| {
+			let live = match (&site.commit, &site.live) {
+				(Some(commit), _) => commit.get(..7).unwrap_or(commit).to_string(),
+				(None, Some(id)) => id.clone(),
+				(None, None) => "-".to_string(),
+			};
+
+			[
+				site.slug.clone(),
+				site.name.clone(),
+				site.github_repo.clone(),
+				live,
+			]
+		})
+		.collect::<Vec<_>>();
+
+	let header = ["SLUG", "NAME", "REPO", "LIVE"].map(String::from);
+	let widths = rows
+		.iter()
+		.fold(header.clone().map(|c| c.len()), |mut widths, row| {
+			for (width, cell) in widths.iter_mut().zip(row) {
+				*width = (*width).max(cell.len());
+			}
+			widths
+		});
+
+	for row in std::iter::once(&header).chain(&rows) {
+		let line = row
+			.iter()
+			.zip(widths)
+			.map(|(cell, width)| format!("{cell:width$}"))
+			.collect::<Vec<_>>()
+			.join("  ");
+
+		println!("{}", line.trim_end());
+	}
+}
+
+fn print_status(status: &SiteStatus) {
+	println!("{} ({})", status.name, status.slug);
+
+	match &status.live {
+		Some(release) => println!("Live: {}", describe(release)),
+		None => println!("Live: nothing deployed"),
+	}
+
+	for deployment in &status.running {
+		println!(
+			"Deploying: {} ({}), triggered by {} at {}",
+			deployment.id,
+			deployment.r#ref.as_deref().unwrap_or("default branch"),
+			deployment.triggered_by,
+			deployment.started_at.format(TIME_FORMAT)
+		);
+	}
+
+	if let Some(failure) = &status.last_failure {
+		println!(
+			"Last failure: {} ({}), triggered by {} at {}: {}",
+			failure.id,
+			failure.r#ref.as_deref().unwrap_or("default branch"),
+			failure.triggered_by,
+			failure.failed_at.format(TIME_FORMAT),
+			failure.error
+		);
+	}
+
+	if !status.releases.is_empty() {
+		println!("Releases:");
+	}
+	for release in &status.releases {
+		let live = status
+			.live
+			.as_ref()
+			.is_some_and(|live| live.id == release.id);
+
+		println!(
+			"  {} {} {}",
+			if live { "*" } else { " " },
+			describe(release),
+			format_size(release.size)
+		);
+	}
+}
+
+fn describe(release: &Release) -> String {
+	let mut description = release.id.clone();
+
+	if let Some(commit) = &release.commit {
+		_ = write!(description, " @ {}", commit.get(..7).unwrap_or(commit));
+	}
+	if let Some(r#ref) = &release.r#ref {
+		_ = write!(description, " ({ref})");
+	}
+	if let Some(artifact) = &release.artifact {
+		_ = write!(
+			description,
+			", artifact sha256:{}",
+			artifact.get(..12).unwrap_or(artifact)
+		);
+	}
+	if let Some(deployed_at) = release.deployed_at {
+		_ = write!(
+			description,
+			", deployed {}",
+			deployed_at.format(TIME_FORMAT)
+		);
+	}
+
+	description
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+	let mut size = bytes as f64;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	if unit == 0 {
+		format!("{bytes} B")
+	} else {
+		format!("{size:.1} {}", UNITS[unit])
+	}
+}
+
+fn parse_var(var: &str) -> Result<(String, String)> {
+	let Some((key, value)) = var.split_once('=') else {
+		bail!("expected KEY=VALUE, got `{var}`");
+	};
+
+	Ok((key.to_string(), value.to_string()))
+}
+
+fn print_log(log: Log) {
+	match log {
+		Log::Info(message) => println!("{message}"),
+		Log::Error(message) => eprintln!("{message}"),
+	}
+}
+
+/// Show how much of the repository has been downloaded (or of the artifact uploaded), on a line
+/// that's redrawn as it grows. Skipped when not writing to a terminal (like in CI), returning
+/// whether it was shown.
+fn print_download(term: &Term, label: &str, progress: DownloadProgress) -> bool {
+	const BAR_WIDTH: usize = 30;
+
+	if !term.is_term() {
+		return false;
+	}
+
+	let mut line = format!("{label}... ");
+	if let Some(total) = progress.total.filter(|total| *total > 0) {
+		let filled = usize::try_from(progress.downloaded.min(total) * BAR_WIDTH as u64 / total)
+			.unwrap_or(BAR_WIDTH);
+
+		_ = write!(
+			line,
+			"[{}{}] {} of {}",
+			"#".repeat(filled),
+			"-".repeat(BAR_WIDTH - filled),
+			format_size(progress.downloaded),
+			format_size(total)
+		);
+	} else {
+		line.push_str(&format_size(progress.downloaded));
+	}
+
+	_ = term.clear_line();
+	_ = term.write_str(&line);
+
+	true
+}
+
+async fn run_deploy(
+	slug: String,
+	r#ref: Option<String>,
+	build: DeployBuild,
+	client: &Client,
+) -> Result<()> {
+	let build = build.validate()?;
+	let github_build = build.github_build();
+
+	let (mut stream, label, downloaded) = if let Some(path) = build.artifact {
+		let artifact = Artifact::open(&path)
+			.await
+			.with_context(|| format!("Could not read {}", path.display()))?;
+		log::info!(
+			"Uploading {} ({}, sha256:{})",
+			path.display(),
+			format_size(artifact.size()),
+			artifact.checksum()
+		);
+
+		(
+			client
+				.deploy_artifact(&slug, r#ref.as_deref(), artifact)
+				.boxed(),
+			"Uploading artifact",
+			"Received artifact",
+		)
+	} else if build.uses_github() {
+		(
+			client
+				.deploy_build(
+					&slug,
+					r#ref.as_deref(),
+					github_build.as_ref(),
+					build.run_id,
+					build.checksum.as_deref(),
+				)
+				.boxed(),
+			"Downloading build",
+			"Downloaded build",
+		)
+	} else {
+		(
+			client.deploy(&slug, r#ref.as_deref()).boxed(),
+			"Downloading repository",
+			"Downloaded repository",
+		)
+	};
+
+	let term = Term::stdout();
+	let mut downloading = false;
 
 	while let Some(event) = stream.next().await {
-		match event? {
-			Ok(Progress::Log(log)) => match log {
-				Log::Info(message) => println!("{message}"),
-				Log::Error(message) => eprintln!("{message}"),
+		let event = event?;
+
+		// the download progress line is redrawn in place, so clear it before printing anything else
+		if downloading && !matches!(event, Ok(Progress::Download(_))) {
+			_ = term.clear_line();
+			downloading = false;
+		}
+
+		match event {
+			Ok(Progress::Download(progress)) => {
+				downloading = print_download(&term, label, progress);
 			},
+			Ok(Progress::Log(log)) => print_log(log),
 			Ok(Progress::Stage(stage)) => match stage {
 				Stage::Deployed => log::info!("Deployed site"),
 				Stage::Migrated => log::info!("Migrated database"),
 				Stage::Starting => log::info!("Starting deployment"),
 				Stage::Optimized => log::info!("Optimized deployment"),
-				Stage::Downloaded => log::info!("Downloaded repository"),
+				Stage::Downloaded => log::info!("{downloaded}"),
 				Stage::DepsInstalled => log::info!("Installed dependencies"),
 			},
 			Err(error) => return Err(error.into()),
@@ -100,3 +601,7 @@ async fn run_deploy(slug: String, r#ref: Option<String>, client: &Client) -> Res
 
 	Ok(())
 }
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+	fs::read(path).with_context(|| format!("Could not read {}", path.display()))
+}
@@ -1,16 +1,37 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
 use async_fn_stream::try_fn_stream;
+use eventsource_stream::Eventsource;
 use futures::{stream::StreamExt, Stream};
-use orbit_types::{ErrorResponse, Progress};
-use reqwest::{header, Response, StatusCode};
+use hmac::{Hmac, Mac};
+use orbit_types::{
+	signing, EnvResponse, EnvUpdate, ErrorResponse, Progress, SiteStatus, SiteSummary,
+	CONTENT_SHA256_HEADER,
+};
+use reqwest::{header, Certificate, Identity, Method, RequestBuilder, Response, StatusCode};
 use reqwest_eventsource::{Event, RequestBuilderExt};
+use sha2::{Digest, Sha256};
+use std::{
+	collections::BTreeMap,
+	io,
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use url::Url;
+use uuid::Uuid;
 
+/// The user agent requests are made with, so they can be told apart in the server's audit log.
+const USER_AGENT: &str = concat!("orbit-client/", env!("CARGO_PKG_VERSION"));
+
+#[allow(clippy::struct_field_names)]
 pub struct Client {
 	base_url: Url,
 	token: String,
 	client: reqwest::Client,
+	signing_key: Option<String>,
+	identity: Option<Identity>,
+	root_certificates: Vec<Certificate>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,6 +45,9 @@ pub enum Error {
 	#[error("Invalid authentication token")]
 	Unauthorized,
 
+	#[error("The authentication token is not allowed to do this")]
+	Forbidden,
+
 	#[error("Could not find the requested site")]
 	SiteNotFound,
 
@@ -35,6 +59,110 @@ pub enum Error {
 
 	#[error("Could not decode the event data")]
 	Decoding(#[from] serde_json::Error),
+
+	#[error("Could not get an OIDC token from GitHub Actions: {0}")]
+	Oidc(String),
+}
+
+/// A build published on GitHub, to deploy instead of the site's repository.
+#[derive(Debug, Clone)]
+pub enum Build {
+	/// The asset with this name, from the release tagged with the deployed ref (or the latest release).
+	ReleaseAsset(String),
+	/// The artifact with this name, from a workflow run.
+	WorkflowArtifact(String),
+}
+
+/// A build to deploy as is, instead of having the server download the repository.
+#[derive(Debug)]
+pub struct Artifact {
+	file: tokio::fs::File,
+	size: u64,
+	checksum: String,
+}
+
+impl Artifact {
+	/// Open a gzipped tarball or zip archive, computing its checksum.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the file can't be read.
+	pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+		let mut file = tokio::fs::File::open(path).await?;
+		let mut hasher = Sha256::new();
+		let mut buffer = vec![0; 64 * 1024];
+		let mut size = 0;
+
+		loop {
+			let read = file.read(&mut buffer).await?;
+			if read == 0 {
+				break;
+			}
+
+			hasher.update(&buffer[..read]);
+			size += read as u64;
+		}
+		file.rewind().await?;
+
+		Ok(Self {
+			file,
+			size,
+			checksum: format!("{:x}", hasher.finalize()),
+		})
+	}
+
+	/// The size of the artifact, in bytes.
+	#[must_use]
+	pub const fn size(&self) -> u64 {
+		self.size
+	}
+
+	/// The hex-encoded SHA-256 checksum of the artifact.
+	#[must_use]
+	pub fn checksum(&self) -> &str {
+		&self.checksum
+	}
+}
+
+/// Request an OIDC token for the given audience from GitHub Actions, to authenticate with
+/// sites that accept them instead of a static token. The workflow needs the `id-token: write` permission.
+///
+/// # Errors
+///
+/// Returns an error if not running in GitHub Actions, or if the token can't be issued.
+pub async fn github_actions_token(audience: &str) -> Result<String, Error> {
+	#[derive(serde::Deserialize)]
+	struct TokenResponse {
+		value: String,
+	}
+
+	let (Ok(url), Ok(request_token)) = (
+		std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL"),
+		std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN"),
+	) else {
+		return Err(Error::Oidc(
+			"not running in GitHub Actions, or the workflow lacks the `id-token: write` permission"
+				.to_string(),
+		));
+	};
+
+	let mut url = Url::parse(&url).map_err(|e| Error::Oidc(e.to_string()))?;
+	url.query_pairs_mut().append_pair("audience", audience);
+
+	let response = reqwest::Client::new()
+		.get(url)
+		.bearer_auth(request_token)
+		.send()
+		.await?;
+
+	if !response.status().is_success() {
+		return Err(Error::Oidc(format!(
+			"GitHub responded with {}",
+			response.status()
+		)));
+	}
+
+	Ok(response.json::<TokenResponse>().await?.value)
 }
 
 impl Client {
@@ -44,28 +172,105 @@ impl Client {
 		Self {
 			token,
 			base_url,
+			signing_key: None,
+			identity: None,
+			root_certificates: vec![],
 			client: reqwest::Client::new(),
 		}
 	}
 
+	/// Present a client certificate, for servers that require mutual TLS.
+	/// The key must be a PKCS#8 PEM (`BEGIN PRIVATE KEY`).
+	///
+	/// # Errors
+	///
+	/// Returns an error if the certificate or key are invalid.
+	pub fn with_identity(mut self, cert: &[u8], key: &[u8]) -> reqwest::Result<Self> {
+		self.identity = Some(Identity::from_pkcs8_pem(cert, key)?);
+		self.client = self.build_client()?;
+
+		Ok(self)
+	}
+
+	/// Trust the certificates in the given PEM bundle, for servers using a private CA.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the bundle contains invalid certificates.
+	pub fn with_root_certificates(mut self, pem: &[u8]) -> reqwest::Result<Self> {
+		self.root_certificates
+			.extend(Certificate::from_pem_bundle(pem)?);
+		self.client = self.build_client()?;
+
+		Ok(self)
+	}
+
+	/// Sign every request with the given key, for servers that require signed requests.
+	#[must_use]
+	pub fn with_signing_key(mut self, key: String) -> Self {
+		self.signing_key = Some(key);
+
+		self
+	}
+
 	/// Deploy a site.
-	#[allow(clippy::missing_panics_doc)]
 	pub fn deploy(
 		&self,
 		name: &str,
 		r#ref: Option<&str>,
 	) -> impl Stream<Item = Result<Result<Progress, orbit_types::Error>, Error>> {
-		let mut stream = self
-			.client
-			.post(
-				self.base_url
-					.join(&format!("/sites/{name}/deploy"))
-					.unwrap(),
-			)
-			.query(&[("ref", r#ref)])
-			.header(header::AUTHORIZATION, format!("Bearer {}", self.token))
-			.eventsource()
-			.unwrap();
+		self.stream_deployment(self.deploy_url(name, r#ref))
+	}
+
+	/// Deploy a build published on GitHub, or the one the site is configured with if none is given.
+	/// Workflow artifacts are taken from the given run, or the latest one that built the ref. If a
+	/// checksum is given, the build has to match it, so the same build can be promoted between sites.
+	pub fn deploy_build(
+		&self,
+		name: &str,
+		r#ref: Option<&str>,
+		build: Option<&Build>,
+		run_id: Option<u64>,
+		checksum: Option<&str>,
+	) -> impl Stream<Item = Result<Result<Progress, orbit_types::Error>, Error>> {
+		let mut url = self.deploy_url(name, r#ref);
+		{
+			let mut query = url.query_pairs_mut();
+			match build {
+				Some(Build::ReleaseAsset(asset)) => {
+					query.append_pair("release_asset", asset);
+				},
+				Some(Build::WorkflowArtifact(artifact)) => {
+					query.append_pair("workflow_artifact", artifact);
+				},
+				None => {},
+			}
+			if let Some(run_id) = run_id {
+				query.append_pair("run_id", &run_id.to_string());
+			}
+			if let Some(checksum) = checksum {
+				query.append_pair("checksum", checksum);
+			}
+		}
+
+		self.stream_deployment(url)
+	}
+
+	fn deploy_url(&self, name: &str, r#ref: Option<&str>) -> Url {
+		let mut url = self.url(&format!("/sites/{name}/deploy"));
+		if let Some(r#ref) = r#ref {
+			url.query_pairs_mut().append_pair("ref", r#ref);
+		}
+
+		url
+	}
+
+	#[allow(clippy::missing_panics_doc)]
+	fn stream_deployment(
+		&self,
+		url: Url,
+	) -> impl Stream<Item = Result<Result<Progress, orbit_types::Error>, Error>> {
+		let mut stream = self.request(Method::POST, url, None).eventsource().unwrap();
 
 		try_fn_stream(|emitter| async move {
 			while let Some(event) = stream.next().await {
@@ -76,6 +281,7 @@ impl Client {
 						match status_code {
 							StatusCode::NOT_FOUND => return Err(Error::SiteNotFound),
 							StatusCode::UNAUTHORIZED => return Err(Error::Unauthorized),
+							StatusCode::FORBIDDEN => return Err(Error::Forbidden),
 							_ => return Err(Error::InvalidResponse(status_code, response)),
 						}
 					},
@@ -84,17 +290,254 @@ impl Client {
 					Err(err) => return Err(err.into()),
 				};
 
-				let response = match event.id.as_ref() {
-					"log" => Ok(Progress::Log(serde_json::from_str(&event.data)?)),
-					"stage" => Ok(Progress::Stage(serde_json::from_str(&event.data)?)),
-					"error" => Err(serde_json::from_str::<ErrorResponse>(&event.data)?.error),
-					_ => return Err(Error::InvalidEvent(format!("{}: {}", event.id, event.data))),
-				};
-
-				emitter.emit(response).await;
+				if let Some(event) = parse_event(&event.id, &event.data)? {
+					emitter.emit(event).await;
+				}
 			}
 
 			unreachable!("The stream should not end without a StreamEnded error");
 		})
 	}
+
+	/// Deploy a site from an uploaded artifact, instead of its repository. The `ref` is
+	/// only recorded, so it's clear what the artifact was built from.
+	pub fn deploy_artifact(
+		&self,
+		name: &str,
+		r#ref: Option<&str>,
+		artifact: Artifact,
+	) -> impl Stream<Item = Result<Result<Progress, orbit_types::Error>, Error>> {
+		let url = self.deploy_url(name, r#ref);
+
+		// the body is streamed, so it's signed (and checked by the server) through its checksum
+		let request = self
+			.authorized(Method::POST, url, &artifact.checksum)
+			.header(CONTENT_SHA256_HEADER, &artifact.checksum)
+			.header(header::CONTENT_LENGTH, artifact.size)
+			.header(header::CONTENT_TYPE, "application/octet-stream")
+			.body(reqwest::Body::from(artifact.file));
+
+		try_fn_stream(|emitter| async move {
+			let response = request.send().await?;
+			match response.status() {
+				StatusCode::NOT_FOUND => return Err(Error::SiteNotFound),
+				StatusCode::UNAUTHORIZED => return Err(Error::Unauthorized),
+				StatusCode::FORBIDDEN => return Err(Error::Forbidden),
+				status if !status.is_success() => {
+					return Err(Error::InvalidResponse(status, response))
+				},
+				_ => {},
+			}
+
+			let mut events = response.bytes_stream().eventsource();
+			while let Some(event) = events.next().await {
+				let event = event.map_err(reqwest_eventsource::Error::from)?;
+
+				if let Some(event) = parse_event(&event.id, &event.data)? {
+					emitter.emit(event).await;
+				}
+			}
+
+			Ok(())
+		})
+	}
+
+	/// List the sites the token can access, along with their live deployment.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the server rejects it.
+	pub async fn sites(&self) -> Result<Vec<SiteSummary>, Error> {
+		self.send(self.request(Method::GET, self.url("/sites"), None))
+			.await
+	}
+
+	/// Get the live release of a site, the releases on disk, and any running or failed deployments.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the server rejects it.
+	pub async fn site_status(&self, name: &str) -> Result<SiteStatus, Error> {
+		self.send(self.request(Method::GET, self.url(&format!("/sites/{name}")), None))
+			.await
+	}
+
+	/// Get the variables in a site's `.env` file, with their values masked.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the server rejects it.
+	pub async fn env(&self, name: &str) -> Result<EnvResponse, Error> {
+		self.send(self.request(Method::GET, self.env_url(name, None), None))
+			.await
+	}
+
+	/// Add, overwrite or remove variables in a site's `.env` file, optionally running
+	/// `php artisan config:cache` on the live deployment afterwards.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the server rejects it.
+	pub async fn update_env(
+		&self,
+		name: &str,
+		update: &EnvUpdate,
+		config_cache: bool,
+	) -> Result<EnvResponse, Error> {
+		self.send(self.request(
+			Method::PATCH,
+			self.env_url(name, Some(config_cache)),
+			Some(serde_json::to_vec(update)?),
+		))
+		.await
+	}
+
+	/// Replace the contents of a site's `.env` file, optionally running
+	/// `php artisan config:cache` on the live deployment afterwards.
+	///
+	/// # Errors
+	///
+	/// Returns an error if the request fails or the server rejects it.
+	pub async fn replace_env(
+		&self,
+		name: &str,
+		vars: &BTreeMap<String, String>,
+		config_cache: bool,
+	) -> Result<EnvResponse, Error> {
+		self.send(self.request(
+			Method::PUT,
+			self.env_url(name, Some(config_cache)),
+			Some(serde_json::to_vec(vars)?),
+		))
+		.await
+	}
+
+	fn env_url(&self, name: &str, config_cache: Option<bool>) -> Url {
+		let mut url = self.url(&format!("/sites/{name}/env"));
+		if let Some(config_cache) = config_cache {
+			url.query_pairs_mut()
+				.append_pair("config_cache", &config_cache.to_string());
+		}
+
+		url
+	}
+
+	fn build_client(&self) -> reqwest::Result<reqwest::Client> {
+		let mut builder = reqwest::Client::builder();
+
+		if let Some(identity) = &self.identity {
+			builder = builder.identity(identity.clone());
+		}
+		for certificate in &self.root_certificates {
+			builder = builder.add_root_certificate(certificate.clone());
+		}
+
+		builder.build()
+	}
+
+	fn url(&self, path: &str) -> Url {
+		self.base_url.join(path).unwrap()
+	}
+
+	/// Build an authenticated request with the given JSON body, signing it if a key was provided.
+	fn request(&self, method: Method, url: Url, json: Option<Vec<u8>>) -> RequestBuilder {
+		let body = json.unwrap_or_default();
+		let mut request = self.authorized(method, url, &format!("{:x}", Sha256::digest(&body)));
+
+		if !body.is_empty() {
+			request = request
+				.header(header::CONTENT_TYPE, "application/json")
+				.body(body);
+		}
+
+		request
+	}
+
+	/// Build an authenticated request for a body with the given SHA-256 hash, signing it if a key was provided.
+	fn authorized(&self, method: Method, url: Url, body_hash: &str) -> RequestBuilder {
+		let signature = self
+			.signing_key
+			.as_ref()
+			.map(|key| sign(key, &method, &url, body_hash));
+
+		let mut request = self
+			.client
+			.request(method, url)
+			.header(header::USER_AGENT, USER_AGENT)
+			.header(header::AUTHORIZATION, format!("Bearer {}", self.token));
+
+		if let Some(headers) = signature {
+			for (name, value) in headers {
+				request = request.header(name, value);
+			}
+		}
+
+		request
+	}
+
+	async fn send<T: serde::de::DeserializeOwned>(
+		&self,
+		request: RequestBuilder,
+	) -> Result<T, Error> {
+		let response = request.send().await?;
+
+		match response.status() {
+			StatusCode::NOT_FOUND => Err(Error::SiteNotFound),
+			StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+			StatusCode::FORBIDDEN => Err(Error::Forbidden),
+			status if !status.is_success() => Err(Error::InvalidResponse(status, response)),
+			_ => Ok(response.json().await?),
+		}
+	}
+}
+
+/// Parse a deployment event, by its id. Events this client doesn't know about are skipped,
+/// so newer servers can report more kinds of progress without breaking it.
+#[allow(clippy::result_large_err)]
+fn parse_event(
+	id: &str,
+	data: &str,
+) -> Result<Option<Result<Progress, orbit_types::Error>>, Error> {
+	Ok(Some(match id {
+		"log" => Ok(Progress::Log(serde_json::from_str(data)?)),
+		"stage" => Ok(Progress::Stage(serde_json::from_str(data)?)),
+		"download" => Ok(Progress::Download(serde_json::from_str(data)?)),
+		"error" => Err(serde_json::from_str::<ErrorResponse>(data)?.error),
+		_ => return Ok(None),
+	}))
+}
+
+/// The headers that sign a request with the given key, for a body with the given SHA-256 hash.
+fn sign(key: &str, method: &Method, url: &Url, body_hash: &str) -> [(&'static str, String); 3] {
+	let timestamp = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	let nonce = Uuid::new_v4().simple().to_string();
+	let path_and_query = url.query().map_or_else(
+		|| url.path().to_string(),
+		|query| format!("{}?{query}", url.path()),
+	);
+
+	let mut mac =
+		Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+	mac.update(
+		signing::payload(
+			timestamp,
+			&nonce,
+			method.as_str(),
+			&path_and_query,
+			body_hash,
+		)
+		.as_bytes(),
+	);
+
+	[
+		(signing::TIMESTAMP_HEADER, timestamp.to_string()),
+		(signing::NONCE_HEADER, nonce),
+		(
+			signing::SIGNATURE_HEADER,
+			format!("v1={:x}", mac.finalize().into_bytes()),
+		),
+	]
 }
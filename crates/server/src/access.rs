@@ -0,0 +1,106 @@
+use axum::{
+	extract::{MatchedPath, Request},
+	middleware::Next,
+	response::Response,
+	Extension,
+};
+use http::{HeaderMap, StatusCode};
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use crate::config::{Access, Config};
+
+/// The address of the connection a request came in on, or `None` for Unix sockets.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer(pub Option<SocketAddr>);
+
+/// The address of the client that made a request, after accounting for trusted proxies.
+/// Clients on a Unix socket that don't forward an address are treated as local.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+/// Resolve the client's address and reject it if it isn't allowed to reach the route.
+pub async fn check(
+	Extension(config): Extension<Arc<Config>>,
+	path: Option<MatchedPath>,
+	mut request: Request,
+	next: Next,
+) -> Result<Response, StatusCode> {
+	let peer = request
+		.extensions()
+		.get::<Peer>()
+		.and_then(|peer| peer.0)
+		.map(|addr| addr.ip());
+	let ip = client_ip(&config.access, peer, request.headers());
+
+	let route = path.as_ref().map(MatchedPath::as_str);
+	if !config.access.allows(route, ip) {
+		tracing::warn!(%ip, route, "Rejected request from disallowed address");
+		return Err(StatusCode::FORBIDDEN);
+	}
+
+	request.extensions_mut().insert(ClientIp(ip));
+
+	Ok(next.run(request).await)
+}
+
+/// Find the client's address, walking `X-Forwarded-For` back from the closest hop
+/// until reaching an address that isn't a trusted proxy.
+fn client_ip(access: &Access, peer: Option<IpAddr>, headers: &HeaderMap) -> IpAddr {
+	let trusted = |ip: &IpAddr| access.trusted_proxies.iter().any(|net| net.contains(ip));
+
+	if let Some(ip) = peer.filter(|ip| !trusted(ip)) {
+		return ip;
+	}
+
+	let forwarded = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+		.collect::<Vec<_>>();
+
+	forwarded
+		.iter()
+		.rev()
+		.find(|ip| !trusted(ip))
+		.or_else(|| forwarded.first())
+		.copied()
+		.or(peer)
+		.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Counts failed authentication attempts per client, so tokens can't be brute-forced.
+#[derive(Debug, Default)]
+pub struct Limiter(Mutex<HashMap<IpAddr, (u32, Instant)>>);
+
+impl Limiter {
+	/// Whether the client has failed to authenticate too many times recently.
+	pub fn is_limited(&self, access: &Access, ip: IpAddr) -> bool {
+		let window = Duration::from_secs(access.auth_failure_window);
+
+		access.max_auth_failures > 0
+			&& self
+				.0
+				.lock()
+				.unwrap()
+				.get(&ip)
+				.is_some_and(|(failures, since)| {
+					since.elapsed() < window && *failures >= access.max_auth_failures
+				})
+	}
+
+	/// Record a failed authentication attempt from the client.
+	pub fn record_failure(&self, access: &Access, ip: IpAddr) {
+		let window = Duration::from_secs(access.auth_failure_window);
+		let mut failures = self.0.lock().unwrap();
+
+		failures.retain(|_, (_, since)| since.elapsed() < window);
+		failures.entry(ip).or_insert_with(|| (0, Instant::now())).0 += 1;
+	}
+}
@@ -0,0 +1,320 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, Extension};
+use chrono::{DateTime, Utc};
+use http::{header, StatusCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+	fs::{self, File, OpenOptions},
+	io::{self, Write},
+	net::IpAddr,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+use crate::{
+	access::ClientIp,
+	auth::Auth,
+	config::{GitHubBuild, Scope, Site},
+};
+
+/// An entry in the append-only audit log.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Entry {
+	/// When the action happened.
+	pub at: DateTime<Utc>,
+	/// The name of the token that performed the action, if the request authenticated.
+	pub actor: Option<String>,
+	/// The address the request came from, if known.
+	pub ip: Option<IpAddr>,
+	/// The user agent the request was made with.
+	pub user_agent: Option<String>,
+	/// The slug of the site the action was performed on, if it was about one.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub site: Option<String>,
+	/// What happened.
+	#[serde(flatten)]
+	pub action: Action,
+	/// Whether it worked.
+	#[serde(flatten)]
+	pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+	/// A deployment was triggered.
+	Deploy {
+		r#ref: Option<String>,
+		/// The SHA-256 checksum of the uploaded artifact, if one was deployed.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		artifact: Option<String>,
+		/// The build published on GitHub that was deployed instead of the repository's source.
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		build: Option<GitHubBuild>,
+	},
+	/// The site's deployment status was read.
+	StatusRead,
+	/// The site's `.env` file was read.
+	EnvRead,
+	/// Variables in the site's `.env` file were added, overwritten or removed.
+	EnvChange {
+		set: Vec<String>,
+		unset: Vec<String>,
+	},
+	/// The site's `.env` file was replaced.
+	EnvReplace { keys: Vec<String> },
+	/// The sites the token can access were listed.
+	SitesList,
+	/// The config was reloaded.
+	ConfigReload,
+	/// The audit log was read.
+	AuditRead,
+	/// A request tried to authenticate, and was rejected.
+	Authenticate { path: String },
+}
+
+impl Action {
+	/// The name the action is logged under.
+	pub const fn name(&self) -> &'static str {
+		match self {
+			Self::Deploy { .. } => "deploy",
+			Self::StatusRead => "status_read",
+			Self::EnvRead => "env_read",
+			Self::EnvChange { .. } => "env_change",
+			Self::EnvReplace { .. } => "env_replace",
+			Self::SitesList => "sites_list",
+			Self::ConfigReload => "config_reload",
+			Self::AuditRead => "audit_read",
+			Self::Authenticate { .. } => "authenticate",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "outcome", content = "error", rename_all = "snake_case")]
+pub enum Outcome {
+	/// The action completed.
+	Success,
+	/// The token wasn't allowed to perform the action.
+	Denied,
+	/// The action was attempted, but failed.
+	Failed(String),
+}
+
+/// The file audit entries are appended to.
+#[derive(Debug)]
+pub struct AuditLog {
+	path: PathBuf,
+	file: Mutex<File>,
+}
+
+impl AuditLog {
+	pub fn open(path: &Path) -> io::Result<Self> {
+		if let Some(parent) = path
+			.parent()
+			.filter(|parent| !parent.as_os_str().is_empty())
+		{
+			fs::create_dir_all(parent)?;
+		}
+
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+		Ok(Self {
+			file: Mutex::new(file),
+			path: path.to_path_buf(),
+		})
+	}
+
+	pub fn append(&self, entry: &Entry) -> io::Result<()> {
+		let mut line = serde_json::to_string(entry)?;
+		line.push('\n');
+
+		self.file.lock().unwrap().write_all(line.as_bytes())
+	}
+
+	/// Every entry in the log, oldest first. Lines that can't be parsed are skipped.
+	pub fn entries(&self) -> io::Result<Vec<Entry>> {
+		let contents = fs::read_to_string(&self.path)?;
+
+		Ok(contents
+			.lines()
+			.filter_map(|line| match serde_json::from_str(line) {
+				Ok(entry) => Some(entry),
+				Err(e) => {
+					tracing::warn!(e = %e, "Skipping invalid audit log entry");
+					None
+				},
+			})
+			.collect())
+	}
+}
+
+/// Records the actions taken by the current request, along with where it came from.
+#[derive(Debug, Clone)]
+pub struct Audit {
+	log: Arc<AuditLog>,
+	ip: Option<IpAddr>,
+	user_agent: Option<String>,
+}
+
+impl Audit {
+	/// Record an action on a site and its outcome.
+	pub fn record(
+		&self,
+		auth: &Auth,
+		site: &Site,
+		action: Action,
+		outcome: Outcome,
+	) -> io::Result<()> {
+		self.append(Some(auth), Some(site), action, outcome)
+	}
+
+	/// Record an action that isn't about a single site and its outcome, by the
+	/// given token (or by an unauthenticated request, if there's none).
+	pub fn record_global(
+		&self,
+		auth: Option<&Auth>,
+		action: Action,
+		outcome: Outcome,
+	) -> io::Result<()> {
+		self.append(auth, None, action, outcome)
+	}
+
+	fn append(
+		&self,
+		auth: Option<&Auth>,
+		site: Option<&Site>,
+		action: Action,
+		outcome: Outcome,
+	) -> io::Result<()> {
+		self.log.append(&Entry {
+			action,
+			outcome,
+			at: Utc::now(),
+			ip: self.ip,
+			actor: auth.map(|auth| auth.name.clone()),
+			site: site.map(Site::slug),
+			user_agent: self.user_agent.clone(),
+		})
+	}
+
+	/// Check the token can perform the action on the site, recording the attempt if it can't.
+	pub fn authorize(
+		&self,
+		auth: &Auth,
+		site: &Site,
+		scope: Scope,
+		action: &Action,
+	) -> Result<(), StatusCode> {
+		auth.authorize(site, scope).inspect_err(|_| {
+			if let Err(e) = self.record(auth, site, action.clone(), Outcome::Denied) {
+				tracing::error!(e = ?e, "Failed to write audit log");
+			}
+		})
+	}
+
+	/// Record the outcome of an action from the handler's result. If the entry can't be
+	/// written, the request fails, so no action goes unrecorded.
+	pub fn finish<T>(
+		&self,
+		auth: &Auth,
+		site: &Site,
+		action: Action,
+		result: Result<T, StatusCode>,
+	) -> Result<T, StatusCode> {
+		self.complete(auth, Some(site), action, result)
+	}
+
+	/// Like [`Audit::finish`], for actions that aren't about a single site.
+	pub fn finish_global<T>(
+		&self,
+		auth: &Auth,
+		action: Action,
+		result: Result<T, StatusCode>,
+	) -> Result<T, StatusCode> {
+		self.complete(auth, None, action, result)
+	}
+
+	fn complete<T>(
+		&self,
+		auth: &Auth,
+		site: Option<&Site>,
+		action: Action,
+		result: Result<T, StatusCode>,
+	) -> Result<T, StatusCode> {
+		let outcome = match &result {
+			Ok(_) => Outcome::Success,
+			Err(status) => Outcome::Failed(status.to_string()),
+		};
+
+		self.append(Some(auth), site, action, outcome)
+			.map_err(|e| {
+				tracing::error!(e = ?e, "Failed to write audit log");
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?;
+
+		result
+	}
+
+	/// Start recording a long-running action, whose outcome is recorded once the returned guard is dropped.
+	pub fn start(self, auth: &Auth, site: &Site, action: Action) -> Pending {
+		Pending {
+			action,
+			audit: self,
+			site: site.clone(),
+			auth: auth.clone(),
+			outcome: Outcome::Failed("interrupted".to_string()),
+		}
+	}
+}
+
+/// An action that's still running. Its outcome is recorded when dropped,
+/// so actions that are interrupted are still logged.
+#[derive(Debug)]
+pub struct Pending {
+	auth: Auth,
+	site: Site,
+	audit: Audit,
+	action: Action,
+	outcome: Outcome,
+}
+
+impl Pending {
+	pub fn set_outcome(&mut self, outcome: Outcome) {
+		self.outcome = outcome;
+	}
+}
+
+impl Drop for Pending {
+	fn drop(&mut self) {
+		let action = self.action.clone();
+		let outcome = self.outcome.clone();
+
+		if let Err(e) = self.audit.record(&self.auth, &self.site, action, outcome) {
+			tracing::error!(e = ?e, "Failed to write audit log");
+		}
+	}
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Audit {
+	type Rejection = StatusCode;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let Extension(log) = Extension::<Arc<AuditLog>>::from_request_parts(parts, state)
+			.await
+			.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+		Ok(Self {
+			log,
+			ip: parts.extensions.get::<ClientIp>().map(|ip| ip.0),
+			user_agent: parts
+				.headers
+				.get(header::USER_AGENT)
+				.and_then(|value| value.to_str().ok())
+				.map(ToString::to_string),
+		})
+	}
+}
+
+impl aide::OperationInput for Audit {}
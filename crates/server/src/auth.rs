@@ -0,0 +1,268 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use anyhow::Result;
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, Extension};
+use axum_extra::{
+	headers::{authorization::Bearer, Authorization},
+	TypedHeader,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use clap::Subcommand;
+use http::StatusCode;
+use sha2::{Digest, Sha256};
+use std::{
+	io::{self, Read},
+	sync::Arc,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+	access::{ClientIp, Limiter},
+	audit::{Action, Audit, Outcome},
+	config::{Config, Scope, Site},
+	oidc::{self, Claims, Verifier},
+};
+
+/// The API token a request was authenticated with.
+#[derive(Debug, Clone)]
+pub struct Auth {
+	/// The name of the token, to identify it in logs.
+	pub name: String,
+	grant: Grant,
+}
+
+#[derive(Debug, Clone)]
+enum Grant {
+	/// A static token from the config.
+	Token {
+		sites: Option<Vec<String>>,
+		scopes: Vec<Scope>,
+	},
+	/// A GitHub Actions OIDC token, whose access depends on each site's `oidc` rules.
+	Oidc(Claims),
+}
+
+impl Auth {
+	/// Whether the token can see the given site at all.
+	pub fn can_access(&self, site: &Site) -> bool {
+		match &self.grant {
+			Grant::Token { sites, .. } => sites.as_ref().is_none_or(|sites| {
+				sites
+					.iter()
+					.any(|s| s == "*" || site.slugs().any(|slug| *s == slug))
+			}),
+			Grant::Oidc(claims) => site
+				.oidc
+				.as_ref()
+				.is_some_and(|rules| rules.allows(site, claims)),
+		}
+	}
+
+	/// Whether the token can access every site, including ones added to the config later.
+	pub fn can_access_all(&self) -> bool {
+		match &self.grant {
+			Grant::Token { sites, .. } => sites
+				.as_ref()
+				.is_none_or(|sites| sites.iter().any(|s| s == "*")),
+			Grant::Oidc(_) => false,
+		}
+	}
+
+	/// Whether the token can perform the given action on every site it can access. OIDC tokens
+	/// only get the scopes granted by each site's rules, so never have any globally.
+	pub fn has_scope(&self, scope: Scope) -> bool {
+		match &self.grant {
+			Grant::Token { scopes, .. } => includes(scopes, scope),
+			Grant::Oidc(_) => false,
+		}
+	}
+
+	/// Check that the token can perform the given action on the given site. Sites the
+	/// token can't access are reported as missing, so their existence isn't leaked.
+	pub fn authorize(&self, site: &Site, scope: Scope) -> Result<(), StatusCode> {
+		if !self.can_access(site) {
+			return Err(StatusCode::NOT_FOUND);
+		}
+
+		let allowed = match &self.grant {
+			Grant::Token { scopes, .. } => includes(scopes, scope),
+			Grant::Oidc(_) => site
+				.oidc
+				.as_ref()
+				.is_some_and(|rules| includes(&rules.scopes, scope)),
+		};
+
+		if !allowed {
+			return Err(StatusCode::FORBIDDEN);
+		}
+
+		Ok(())
+	}
+}
+
+fn includes(scopes: &[Scope], scope: Scope) -> bool {
+	scopes.iter().any(|s| *s == scope || *s == Scope::Admin)
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Auth {
+	type Rejection = StatusCode;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let result = Self::authenticate_request(parts, state).await;
+
+		if let Err(status @ (StatusCode::UNAUTHORIZED | StatusCode::TOO_MANY_REQUESTS)) = result {
+			let audit = Audit::from_request_parts(parts, state).await?;
+			let action = Action::Authenticate {
+				path: parts.uri.path().to_string(),
+			};
+
+			if let Err(e) = audit.record_global(None, action, Outcome::Failed(status.to_string())) {
+				tracing::error!(e = ?e, "Failed to write audit log");
+			}
+		}
+
+		result
+	}
+}
+
+impl Auth {
+	async fn authenticate_request<S: Send + Sync>(
+		parts: &mut Parts,
+		state: &S,
+	) -> Result<Self, StatusCode> {
+		let TypedHeader(Authorization(bearer)) =
+			TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+				.await
+				.map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+		let (Ok(Extension(config)), Ok(Extension(limiter))) = (
+			Extension::<Arc<Config>>::from_request_parts(parts, state).await,
+			Extension::<Arc<Limiter>>::from_request_parts(parts, state).await,
+		) else {
+			return Err(StatusCode::INTERNAL_SERVER_ERROR);
+		};
+
+		let ip = parts.extensions.get::<ClientIp>().map(|ip| ip.0);
+		let path = parts.uri.path().to_string();
+
+		if let Some(ip) = ip.filter(|ip| limiter.is_limited(&config.access, *ip)) {
+			tracing::warn!(%ip, path, "Too many failed authentication attempts");
+			return Err(StatusCode::TOO_MANY_REQUESTS);
+		}
+
+		let auth = if let Some(oidc) = config
+			.oidc
+			.as_ref()
+			.filter(|_| oidc::is_jwt(bearer.token()))
+		{
+			let Extension(verifier) = Extension::<Arc<Verifier>>::from_request_parts(parts, state)
+				.await
+				.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+			match verifier.verify(oidc, bearer.token()).await {
+				Ok(claims) => Some(Self {
+					name: format!("oidc:{}", claims.sub),
+					grant: Grant::Oidc(claims),
+				}),
+				Err(e) => {
+					tracing::warn!(e = %e, "Rejected OIDC token");
+					None
+				},
+			}
+		} else {
+			authenticate(&config, bearer.token())
+		};
+
+		auth.ok_or_else(|| {
+			tracing::warn!(ip = ?ip, path, "Failed authentication attempt");
+			if let Some(ip) = ip {
+				limiter.record_failure(&config.access, ip);
+			}
+
+			StatusCode::UNAUTHORIZED
+		})
+	}
+}
+
+impl aide::OperationInput for Auth {}
+
+/// Find the token matching the given value. Every configured token is checked in constant
+/// time, so response times don't leak which (or whether a) token matched.
+fn authenticate(config: &Config, token: &str) -> Option<Auth> {
+	let hash = hash(token);
+	let mut found = None;
+
+	if let Some(legacy) = &config.token {
+		if bool::from(hash.as_bytes().ct_eq(self::hash(legacy).as_bytes())) {
+			let auth = Auth {
+				name: "default".to_string(),
+				grant: Grant::Token {
+					sites: None,
+					scopes: vec![Scope::Admin],
+				},
+			};
+
+			found = Some((auth, None));
+		}
+	}
+
+	for candidate in &config.tokens {
+		if bool::from(hash.as_bytes().ct_eq(candidate.hash.as_bytes())) {
+			let auth = Auth {
+				name: candidate.name.clone(),
+				grant: Grant::Token {
+					scopes: candidate.scopes.clone(),
+					sites: Some(candidate.sites.clone()),
+				},
+			};
+
+			found = Some((auth, candidate.expires_at));
+		}
+	}
+
+	let (auth, expires_at) = found?;
+	if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+		tracing::warn!(token = auth.name, "Rejected expired token");
+		return None;
+	}
+
+	Some(auth)
+}
+
+/// The hex-encoded SHA-256 hash of a token, as stored in the config.
+pub fn hash(token: &str) -> String {
+	format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Generate a new random token, along with the hash to put in your config.
+	Generate,
+
+	/// Print the hash of a token read from stdin.
+	Hash,
+}
+
+impl Command {
+	pub fn run(self) -> Result<()> {
+		match self {
+			Self::Generate => {
+				let mut bytes = [0; 32];
+				OsRng.fill_bytes(&mut bytes);
+				let token = STANDARD.encode(bytes);
+
+				println!("token: {token}");
+				println!("hash:  {}", hash(&token));
+			},
+			Self::Hash => {
+				let mut token = String::new();
+				io::stdin().read_to_string(&mut token)?;
+
+				println!("{}", hash(token.trim_end_matches(['\r', '\n'])));
+			},
+		}
+
+		Ok(())
+	}
+}
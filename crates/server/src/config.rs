@@ -1,19 +1,95 @@
-use anyhow::{bail, Result};
-use axum::Extension;
-use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+use http::StatusCode;
+use indexmap::IndexMap;
+use ipnet::IpNet;
+use schemars::{schema::RootSchema, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use slug::slugify;
 use std::{
+	collections::{HashMap, HashSet},
+	env,
+	ffi::{OsStr, OsString},
+	fs, io,
+	net::{IpAddr, SocketAddr},
 	path::{Path, PathBuf},
 	sync::Arc,
 };
+use toml_edit::{DocumentMut, ImDocument};
 
-use crate::deploy::Deployer;
+use crate::{
+	deploy::Deployer,
+	github::{self, App, Credentials, TokenCache},
+	interpolate::{self, Unset},
+	migrate,
+	oidc::Claims,
+	redact::Redactor,
+	secrets::{self, Cipher, Secret},
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
-	version: usize,
-	pub token: String,
+	/// The version of the config format. Older versions are upgraded when loaded.
+	version: i64,
+	/// A single token with full access to every site. Prefer `tokens`, which can be scoped.
+	pub token: Option<String>,
+	/// A file to read `token` from instead, like a Docker or systemd secret.
+	/// Relative paths are resolved from the config file's directory.
+	pub token_file: Option<PathBuf>,
+	/// API tokens, each with access to some sites and actions.
+	#[serde(default)]
+	pub tokens: Vec<Token>,
+	/// A file holding the key used to decrypt `secrets`. `$ORBIT_SECRETS_KEY` takes precedence.
+	/// Relative paths are resolved from the config file's directory.
+	pub secrets_key_file: Option<PathBuf>,
+	/// Encrypted secrets, which sites can reference from their `env`, `commands` and `github_token`,
+	/// and the config from `github.token`.
+	/// Manage them with `orbit-server secrets`.
+	#[serde(default)]
+	pub secrets: IndexMap<String, String>,
+	/// How to reach GitHub and authenticate with it when downloading repositories.
+	#[serde(default)]
+	pub github: GitHub,
+	/// Settings for authenticating GitHub Actions workflows with OIDC tokens instead of static ones.
+	pub oidc: Option<Oidc>,
+	/// Require API requests to be signed with a shared key, on top of their token.
+	pub signing: Option<Signing>,
+	/// The addresses to listen on. Defaults to `$PORT` (or 8000) on every IPv4 interface.
+	/// Ignored when started through systemd socket activation.
+	#[serde(default)]
+	pub listen: Vec<Listen>,
+	/// Serve the API over HTTPS, optionally requiring client certificates.
+	pub tls: Option<Tls>,
+	/// Where to append the audit log of every API action, as JSON lines. Relative paths
+	/// are resolved from the config file's directory.
+	#[serde(default = "default_audit_log")]
+	pub audit_log: PathBuf,
+	/// The largest artifact that can be uploaded to deploy, in bytes. Defaults to 1 GiB.
+	#[serde(default = "default_max_artifact_size")]
+	pub max_artifact_size: u64,
+	/// Which clients can reach the API, and how often they can fail to authenticate.
+	#[serde(default)]
+	pub access: Access,
+	/// Regular expressions for values to mask in deployment logs, for every site.
+	/// If a pattern has a `secret` group, only that group is masked.
+	#[serde(default)]
+	pub redact_patterns: Vec<String>,
+	/// Other files to load sites from, as glob patterns like `sites.d/*.toml` relative to this
+	/// file's directory. Directories include every `.toml` file in them. Each file can only
+	/// contain `sites`.
+	#[serde(default)]
+	pub include: Vec<String>,
+	/// The sites to deploy, keyed by their slug.
+	#[serde(default, deserialize_with = "keyed_sites")]
+	#[schemars(
+		with = "IndexMap<String, Site>",
+		default = "IndexMap::<String, Site>::new"
+	)]
 	pub sites: Vec<Site>,
+	/// The position of each site in `sites`, keyed by its slug and aliases.
+	#[serde(skip)]
+	site_index: HashMap<String, usize>,
 }
 
 impl Config {
@@ -26,68 +102,1208 @@ impl Config {
 			));
 		}
 
-		let file = std::fs::read_to_string(path)?;
-		let config: Self = toml::from_str(&file)?;
+		let path = path.as_ref();
+		let (contents, mut document) = read(path)?;
+		let version = migrate::upgrade(&mut document)
+			.map_err(|e| anyhow::anyhow!("{}: {e:#}", path.display()))?;
+		if version < migrate::LATEST {
+			tracing::warn!(
+				"{} uses version {version} of the config format. Run `orbit-server config migrate` to upgrade it.",
+				path.display()
+			);
+		}
 
-		if config.version != 1 {
-			return Err(anyhow::anyhow!("Unsupported version: {}", config.version));
+		let mut config: Self = deserialize(path, &contents, &document)?;
+		for site in &mut config.sites {
+			site.source = path.to_path_buf();
 		}
 
+		if let Some(dir) = path.parent() {
+			config.audit_log = dir.join(&config.audit_log);
+			config.secrets_key_file = config.secrets_key_file.map(|file| dir.join(file));
+		}
+
+		config.read_value_files(path.parent().unwrap_or_else(|| Path::new("")))?;
+
+		for file in config.included_files(path)? {
+			let (contents, mut document) = read(&file)?;
+			migrate::upgrade_sites(&mut document, version)
+				.map_err(|e| anyhow::anyhow!("{}: {e:#}", file.display()))?;
+
+			let SiteFile { sites } = deserialize(&file, &contents, &document)?;
+
+			config.sites.extend(sites.into_iter().map(|mut site| {
+				site.source.clone_from(&file);
+				site
+			}));
+		}
+
+		config.index_sites();
+		config.unlock_secrets()?;
+		config.resolve_github(path.parent().unwrap_or_else(|| Path::new("")))?;
+		config.build_redactors()?;
+
 		Ok(config)
 	}
 
+	/// Read the values that were given as paths to files holding them.
+	fn read_value_files(&mut self, dir: &Path) -> Result<()> {
+		if let Some(file) = &self.token_file {
+			if self.token.is_some() {
+				bail!("Only one of `token` and `token_file` can be set");
+			}
+
+			self.token = Some(read_value(&dir.join(file))?);
+		}
+
+		if let Some(signing) = &mut self.signing {
+			if let Some(file) = &signing.key_file {
+				if !signing.key.is_empty() {
+					bail!("Only one of `signing.key` and `signing.key_file` can be set");
+				}
+
+				signing.key = read_value(&dir.join(file))?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The files matching the `include` patterns, resolved from the directory of the config file at `path`.
+	pub fn included_files(&self, path: &Path) -> Result<Vec<PathBuf>> {
+		included_files(path, &self.include)
+	}
+
+	/// Find a site by its slug or one of its aliases.
+	pub fn site(&self, slug: &str) -> Option<Site> {
+		self.site_index
+			.get(slug)
+			.map(|&index| self.sites[index].clone())
+	}
+
+	/// Index the sites by their slugs, so requests don't have to scan them. If several
+	/// sites share a slug the first one wins, and `validate` rejects the config.
+	fn index_sites(&mut self) {
+		self.site_index.clear();
+
+		for (index, site) in self.sites.iter().enumerate() {
+			for slug in site.slugs() {
+				self.site_index.entry(slug).or_insert(index);
+			}
+		}
+	}
+
+	/// Decrypt the secrets each site references, so they're available when deploying it.
+	fn unlock_secrets(&mut self) -> Result<()> {
+		let mut cipher = None;
+
+		for site in &mut self.sites {
+			let names = site
+				.secret_references()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>();
+
+			for name in names {
+				let Some(encrypted) = self.secrets.get(&name) else {
+					bail!("Site {} references unknown secret {name}", site.name);
+				};
+
+				if cipher.is_none() {
+					cipher = Some(Cipher::load(self.secrets_key_file.as_deref())?);
+				}
+
+				let secret = cipher.as_ref().unwrap().decrypt(&name, encrypted)?;
+				site.secrets.insert(name, secret);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Work out how each site's repository is accessed: with the site's own token, as the
+	/// configured GitHub App, or with the global token (in that order).
+	fn resolve_github(&mut self, dir: &Path) -> Result<()> {
+		let app = self
+			.github
+			.app
+			.as_ref()
+			.map(|app| App::load(app, dir).map(Arc::new))
+			.transpose()?;
+
+		let token = match (&self.github.token, env::var("GITHUB_TOKEN")) {
+			(Some(EnvValue::Plain(token)), _) => {
+				Some(("`github.token`", Secret::from(token.clone())))
+			},
+			(Some(EnvValue::Secret { secret }), _) => {
+				let encrypted = self.secrets.get(secret).with_context(|| {
+					format!("`github.token` references unknown secret {secret}")
+				})?;
+				let token =
+					Cipher::load(self.secrets_key_file.as_deref())?.decrypt(secret, encrypted)?;
+
+				Some(("`github.token`", token))
+			},
+			(None, Ok(token)) => Some(("$GITHUB_TOKEN", Secret::from(token))),
+			(None, Err(_)) => None,
+		};
+
+		for site in &mut self.sites {
+			let api_url = &self.github.api_url;
+
+			site.github = match (&site.github_token, &app, &token) {
+				(Some(value), _, _) => {
+					let token = match value {
+						EnvValue::Plain(token) => Secret::from(token.clone()),
+						EnvValue::Secret { secret } => site.secret(secret)?.clone(),
+					};
+
+					Some(Credentials::token(
+						api_url,
+						format!("The `github_token` of site {}", site.name),
+						token,
+					))
+				},
+				(None, Some(app), _) => Some(Credentials::app(api_url, app.clone())),
+				(None, None, Some((source, token))) => Some(Credentials::token(
+					api_url,
+					(*source).to_string(),
+					token.clone(),
+				)),
+				(None, None, None) => None,
+			};
+		}
+
+		Ok(())
+	}
+
+	/// Set up the redactor each site's deployment logs go through, so they never leak Orbit's or the site's secrets.
+	fn build_redactors(&mut self) -> Result<()> {
+		for site in &mut self.sites {
+			let patterns = [&self.redact_patterns[..], &site.redact_patterns[..]].concat();
+			site.redactor = Redactor::new(&patterns).map_err(|e| {
+				anyhow::anyhow!("Invalid redact pattern for site {}: {e}", site.name)
+			})?;
+
+			if let Some(token) = &self.token {
+				site.redactor.add_value(token);
+			}
+			if let Some(signing) = &self.signing {
+				site.redactor.add_value(&signing.key);
+			}
+			if let Some(token) = site.github.as_ref().and_then(Credentials::static_token) {
+				site.redactor.add_value(token);
+			}
+			for secret in site.secrets.values() {
+				site.redactor.add_value(secret.expose());
+			}
+			for (key, value) in &site.env {
+				if let EnvValue::Plain(value) = value {
+					site.redactor.add_var(key, value);
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The JSON Schema of the config file, documented with the same comments as its fields.
+	pub fn schema() -> RootSchema {
+		let mut schema = schemars::schema_for!(Self);
+		schema.schema.metadata().title = Some("Orbit config".to_string());
+
+		schema
+	}
+
+	/// Check the config is usable, reporting every problem with it at once.
 	pub fn validate(self) -> Result<Self> {
-		if std::env::var("GITHUB_TOKEN").is_err() {
-			bail!("$GITHUB_TOKEN is not set");
+		match &self.errors()[..] {
+			[] => Ok(self),
+			[error] => bail!("{error}"),
+			errors => bail!(
+				"Found {} problems in the config:\n{}",
+				errors.len(),
+				errors
+					.iter()
+					.map(|error| format!("  - {error}"))
+					.collect::<Vec<_>>()
+					.join("\n")
+			),
 		}
+	}
+
+	/// Every problem with the config.
+	pub fn errors(&self) -> Vec<String> {
+		let mut errors = vec![];
 
-		if self.token.len() < 32 {
-			bail!("Orbit token is too short. Must be at least 32 characters long.");
+		if !self.github.api_url.starts_with("https://")
+			&& !self.github.api_url.starts_with("http://")
+		{
+			errors.push(format!(
+				"Invalid `github.api_url` {}. Must be an http(s) URL.",
+				self.github.api_url
+			));
 		}
 
-		self.sites.iter().try_for_each(|site| {
-			if !site.github_repo.contains('/') {
-				bail!(
-					"Invalid github_repo for site {}. Must be in the format of owner/repo",
+		if self.token.is_none() && self.tokens.is_empty() {
+			errors.push("No API tokens configured. Add one to `tokens`.".to_string());
+		}
+
+		if self.token.as_ref().is_some_and(|token| token.len() < 32) {
+			errors
+				.push("Orbit token is too short. Must be at least 32 characters long.".to_string());
+		}
+
+		if self
+			.signing
+			.as_ref()
+			.is_some_and(|signing| signing.key.len() < 32)
+		{
+			errors
+				.push("Signing key is too short. Must be at least 32 characters long.".to_string());
+		}
+
+		for (i, token) in self.tokens.iter().enumerate() {
+			if token.hash.len() != 64 || !token.hash.chars().all(|c| c.is_ascii_hexdigit()) {
+				errors.push(format!(
+					"Invalid hash for token {}. Must be a hex-encoded SHA-256 hash.",
+					token.name
+				));
+			}
+
+			if self.tokens[..i].iter().any(|t| t.name == token.name) {
+				errors.push(format!("Duplicate token name {}", token.name));
+			}
+		}
+
+		let mut slugs = HashMap::new();
+		for (index, site) in self.sites.iter().enumerate() {
+			for slug in site.slugs() {
+				if slug.is_empty() || slugify(&slug) != slug {
+					errors.push(format!(
+						"Invalid slug `{slug}` for site {}. Slugs can only contain lowercase letters, numbers and dashes.",
+						site.name
+					));
+				}
+
+				match slugs.insert(slug.clone(), index) {
+					Some(other) if other == index => {
+						errors.push(format!(
+							"Site {} uses the slug {slug} more than once",
+							site.name
+						));
+					},
+					Some(other) => errors.push(format!(
+						"Sites {} (in {}) and {} (in {}) both use the slug {slug}",
+						self.sites[other].name,
+						self.sites[other].source.display(),
+						site.name,
+						site.source.display()
+					)),
+					None => {},
+				}
+			}
+
+			if site.oidc.is_some() && self.oidc.is_none() {
+				errors.push(format!(
+					"Site {} accepts OIDC tokens, but `oidc` is not configured",
 					site.name
+				));
+			}
+
+			if site.github.is_none() {
+				errors.push(format!(
+					"Site {} has no GitHub credentials. Set $GITHUB_TOKEN, `github.token`, `github.app` or the site's `github_token`.",
+					site.name
+				));
+			}
+
+			errors.extend(site.errors());
+		}
+
+		errors
+	}
+
+	/// Problems with this machine's setup for the sites, like missing programs or unwritable
+	/// paths. Only `config check` reports these, since they don't stop the server from starting.
+	pub fn warnings(&self) -> Vec<String> {
+		self.sites.iter().flat_map(Site::warnings).collect()
+	}
+
+	/// Check that each site's GitHub credentials can access its repository.
+	pub async fn github_errors(&self) -> Vec<String> {
+		let client = github::client();
+		let cache = TokenCache::default();
+
+		let mut errors = vec![];
+		let mut rejected = HashSet::new();
+		for site in &self.sites {
+			let Some(credentials) = &site.github else {
+				continue;
+			};
+			if rejected.contains(&credentials.source) {
+				continue;
+			}
+
+			let token = match credentials
+				.access_token(&client, &cache, &site.github_repo)
+				.await
+			{
+				Ok(token) => token,
+				Err(e) => {
+					errors.push(format!(
+						"Could not get a token from {} for site {}: {e:#}",
+						credentials.source, site.name
+					));
+					continue;
+				},
+			};
+
+			let response = client
+				.get(format!(
+					"{}/repos/{}",
+					credentials.api_url, site.github_repo
+				))
+				.bearer_auth(token.expose())
+				.send()
+				.await;
+
+			match response.map(|response| response.status()) {
+				Ok(status) if status.is_success() => {},
+				Ok(StatusCode::UNAUTHORIZED) => {
+					errors.push(format!("{} was rejected by GitHub", credentials.source));
+					rejected.insert(credentials.source.clone());
+				},
+				Ok(status) => errors.push(format!(
+					"{} can't access {} for site {} ({status})",
+					credentials.source, site.github_repo, site.name
+				)),
+				Err(e) => errors.push(format!(
+					"Could not check access to {} for site {}: {e}",
+					site.github_repo, site.name
+				)),
+			}
+		}
+
+		errors
+	}
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Rewrite the config, and the files it includes, in the latest format.
+	Migrate,
+
+	/// Load the config and report every problem with it, warning about missing programs
+	/// or unwritable site paths on this machine.
+	Check {
+		/// Also check that each site's GitHub credentials can access its repository.
+		#[arg(long)]
+		online: bool,
+	},
+
+	/// Print the JSON Schema of the config, for editors to validate and autocomplete it with.
+	Schema,
+}
+
+impl Command {
+	pub async fn run(self, config_path: &Path) -> Result<()> {
+		match self {
+			Self::Migrate => migrate(config_path),
+			Self::Schema => {
+				println!("{}", serde_json::to_string_pretty(&Config::schema())?);
+				Ok(())
+			},
+			Self::Check { online } => {
+				let config = Config::load(config_path)?;
+
+				let mut errors = config.errors();
+				if online {
+					errors.extend(config.github_errors().await);
+				}
+
+				for warning in config.warnings() {
+					eprintln!("Warning: {warning}");
+				}
+
+				if errors.is_empty() {
+					println!("{} is valid", config_path.display());
+					return Ok(());
+				}
+
+				for error in &errors {
+					eprintln!("{error}");
+				}
+				bail!(
+					"Found {} problems in {}",
+					errors.len(),
+					config_path.display()
 				);
+			},
+		}
+	}
+}
+
+/// Upgrade the config file at the given path and the files it includes to the latest format,
+/// without expanding environment variables. Nothing is written unless every file can be upgraded.
+fn migrate(path: &Path) -> Result<()> {
+	let mut document = fs::read_to_string(path)
+		.with_context(|| format!("Could not read {}", path.display()))?
+		.parse::<DocumentMut>()?;
+	let version = migrate::upgrade(&mut document)
+		.with_context(|| format!("Could not migrate {}", path.display()))?;
+
+	if version == migrate::LATEST {
+		println!("{} is already on version {version}", path.display());
+		return Ok(());
+	}
+
+	let include = document
+		.get("include")
+		.and_then(|include| include.as_array())
+		.map(|include| {
+			include
+				.iter()
+				.filter_map(|pattern| pattern.as_str())
+				.map(ToString::to_string)
+				.collect::<Vec<_>>()
+		})
+		.unwrap_or_default();
+
+	let mut migrated = vec![(path.to_path_buf(), document)];
+	for file in included_files(path, &include)? {
+		let mut document = fs::read_to_string(&file)
+			.with_context(|| format!("Could not read {}", file.display()))?
+			.parse::<DocumentMut>()?;
+		migrate::upgrade_sites(&mut document, version)
+			.with_context(|| format!("Could not migrate {}", file.display()))?;
+
+		migrated.push((file, document));
+	}
+
+	for (file, document) in migrated {
+		fs::write(&file, document.to_string())?;
+		println!(
+			"Migrated {} from version {version} to {}",
+			file.display(),
+			migrate::LATEST
+		);
+	}
+
+	Ok(())
+}
+
+/// A file included from the main config, defining extra sites.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SiteFile {
+	#[serde(default, deserialize_with = "keyed_sites")]
+	sites: Vec<Site>,
+}
+
+/// Read a TOML file as a document, expanding the environment variables it references.
+/// The file's original contents are returned too, to point errors at the right line.
+fn read(path: &Path) -> Result<(String, DocumentMut)> {
+	let contents =
+		fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+	let document = ImDocument::parse(contents.as_str()).map_err(|e| {
+		located(
+			path,
+			&contents,
+			e.span().map(|span| span.start),
+			e.message(),
+		)
+	})?;
+
+	let document = interpolate::expand(document).map_err(|Unset { name, span }| {
+		// point at the reference itself, if it's written out plainly in the value
+		let offset = span.map(|span| {
+			contents[span.clone()]
+				.find(&format!("${{{name}"))
+				.map_or(span.start, |offset| span.start + offset)
+		});
+
+		located(
+			path,
+			&contents,
+			offset,
+			&format!("environment variable {name} is not set, and has no default"),
+		)
+	})?;
+
+	Ok((contents, document))
+}
+
+/// Deserialize a document read with [`read`]. Errors are pointed at the original contents, through
+/// any expanded variables. Documents that were upgraded from an older version may have moved
+/// around, so their errors don't point at a line.
+fn deserialize<T: DeserializeOwned>(
+	path: &Path,
+	contents: &str,
+	document: &DocumentMut,
+) -> Result<T> {
+	let document = document.to_string();
+
+	toml::from_str(&document).map_err(|e| {
+		let offset = e
+			.span()
+			.and_then(|span| interpolate::original_offset(contents, &document, span.start));
+
+		located(path, contents, offset, e.message())
+	})
+}
+
+/// The files matching the `include` patterns, resolved from the directory of the config file at `path`.
+fn included_files(path: &Path, include: &[String]) -> Result<Vec<PathBuf>> {
+	let dir = path.parent().unwrap_or_else(|| Path::new(""));
+	let mut files = vec![];
+
+	for pattern in include {
+		let mut pattern = dir.join(pattern);
+		if pattern.is_dir() {
+			pattern.push("*.toml");
+		}
+
+		let matches = glob::glob(&pattern.to_string_lossy())
+			.with_context(|| format!("Invalid include pattern {}", pattern.display()))?;
+
+		for file in matches {
+			let file = file?;
+			if !files.contains(&file) {
+				files.push(file);
 			}
+		}
+	}
 
-			Ok(())
-		})?;
+	Ok(files)
+}
 
-		Ok(self)
+/// Sites are keyed by their slug, which is kept on each site.
+fn keyed_sites<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Site>, D::Error> {
+	Ok(IndexMap::<String, Site>::deserialize(deserializer)?
+		.into_iter()
+		.map(|(slug, mut site)| {
+			site.slug = slug;
+			site
+		})
+		.collect())
+}
+
+/// Read a value from a file, ignoring the trailing newline most tools add.
+fn read_value(path: &Path) -> Result<String> {
+	let value =
+		fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+	Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn located(path: &Path, contents: &str, offset: Option<usize>, message: &str) -> anyhow::Error {
+	let message = message.trim_end();
+	let Some(offset) = offset else {
+		return anyhow::anyhow!("{}: {message}", path.display());
+	};
+
+	let before = &contents[..offset];
+	let line = before.matches('\n').count() + 1;
+	let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+
+	anyhow::anyhow!("{}:{line}:{column}: {message}", path.display())
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Token {
+	/// A name to identify the token by in logs.
+	pub name: String,
+	/// The hex-encoded SHA-256 hash of the token. Generate one with `orbit-server tokens generate`.
+	#[serde(deserialize_with = "lowercase")]
+	pub hash: String,
+	/// The slugs of the sites this token can access, or `*` for all of them.
+	pub sites: Vec<String>,
+	/// The actions this token can perform.
+	pub scopes: Vec<Scope>,
+	/// When the token stops being valid, as an RFC 3339 timestamp.
+	pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+	/// Trigger deployments.
+	Deploy,
+	/// Read information about sites.
+	Read,
+	/// Everything, including changing a site's environment.
+	Admin,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Signing {
+	/// The key requests are signed with, shared with the clients.
+	#[serde(default)]
+	pub key: String,
+	/// A file to read `key` from instead. Relative paths are resolved from the config file's directory.
+	pub key_file: Option<PathBuf>,
+	/// Whether to reject unsigned requests. If disabled, only requests that include a signature are checked.
+	#[serde(default = "default_signing_required")]
+	pub required: bool,
+	/// How far off (in seconds) a request's timestamp can be from the server's clock.
+	#[serde(default = "default_signing_max_skew")]
+	pub max_skew: u64,
+}
+
+const fn default_signing_required() -> bool {
+	true
+}
+
+const fn default_signing_max_skew() -> u64 {
+	300
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Access {
+	/// Proxies trusted to report the client's address in `X-Forwarded-For`. Connections over a
+	/// Unix socket are always trusted, and treated as local if they don't forward an address.
+	#[serde(default, deserialize_with = "networks")]
+	#[schemars(with = "Vec<String>")]
+	pub trusted_proxies: Vec<IpNet>,
+	/// Rules for every route.
+	#[serde(flatten)]
+	pub rules: IpRules,
+	/// Extra rules for specific routes, keyed by their path, like `/sites/:site/deploy`.
+	#[serde(default)]
+	pub routes: IndexMap<String, IpRules>,
+	/// How many times a client can fail to authenticate within `auth_failure_window` before
+	/// its requests are rejected. Set to 0 to disable the limit.
+	#[serde(default = "default_max_auth_failures")]
+	pub max_auth_failures: u32,
+	/// How long (in seconds) failed authentication attempts are remembered for.
+	#[serde(default = "default_auth_failure_window")]
+	pub auth_failure_window: u64,
+}
+
+impl Access {
+	/// Whether a client can reach the route with the given path.
+	pub fn allows(&self, route: Option<&str>, ip: IpAddr) -> bool {
+		self.rules.allows(ip)
+			&& route
+				.and_then(|route| self.routes.get(route))
+				.is_none_or(|rules| rules.allows(ip))
 	}
+}
 
-	pub fn extension(self) -> Extension<Arc<Self>> {
-		Extension(Arc::new(self))
+impl Default for Access {
+	fn default() -> Self {
+		Self {
+			rules: IpRules::default(),
+			routes: IndexMap::new(),
+			trusted_proxies: vec![],
+			max_auth_failures: default_max_auth_failures(),
+			auth_failure_window: default_auth_failure_window(),
+		}
 	}
 }
 
-pub trait SiteCollectionExt {
-	fn find(&self, slug: &str) -> Option<Site>;
+fn default_audit_log() -> PathBuf {
+	PathBuf::from("audit.log")
+}
+
+const fn default_max_artifact_size() -> u64 {
+	1024 * 1024 * 1024
+}
+
+const fn default_max_auth_failures() -> u32 {
+	10
 }
 
-impl SiteCollectionExt for Vec<Site> {
-	fn find(&self, slug: &str) -> Option<Site> {
-		self.iter()
-			.find(|site| slugify(&site.name) == slug)
-			.cloned()
+const fn default_auth_failure_window() -> u64 {
+	900
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct IpRules {
+	/// If not empty, only clients in these networks are allowed.
+	#[serde(default, deserialize_with = "networks")]
+	#[schemars(with = "Vec<String>")]
+	pub allow: Vec<IpNet>,
+	/// Clients in these networks are always rejected.
+	#[serde(default, deserialize_with = "networks")]
+	#[schemars(with = "Vec<String>")]
+	pub deny: Vec<IpNet>,
+}
+
+impl IpRules {
+	pub fn allows(&self, ip: IpAddr) -> bool {
+		!self.deny.iter().any(|net| net.contains(&ip))
+			&& (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
 	}
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Listen {
+	/// A TCP address, like `127.0.0.1:8000` or `[::]:8000`.
+	Tcp(SocketAddr),
+	/// A Unix domain socket.
+	Unix {
+		path: PathBuf,
+		/// The socket's permissions, like `0o660`. Defaults to the process' umask.
+		mode: Option<u32>,
+	},
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Tls {
+	/// The PEM-encoded certificate chain to serve. Changes are picked up without a restart.
+	pub cert: PathBuf,
+	/// The PEM-encoded private key for the certificate.
+	pub key: PathBuf,
+	/// A PEM bundle of the CAs client certificates must be issued by. When set, clients without a valid certificate are rejected.
+	pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct GitHub {
+	/// The base URL of GitHub's REST API. For GitHub Enterprise Server, it's like `https://github.example.com/api/v3`.
+	#[serde(default = "default_github_api_url")]
+	pub api_url: String,
+	/// The token used to download repositories, for sites without their own. Defaults to `$GITHUB_TOKEN`.
+	pub token: Option<EnvValue>,
+	/// Authenticate as a GitHub App instead of with a token, for sites without their own.
+	pub app: Option<GitHubApp>,
+}
+
+impl Default for GitHub {
+	fn default() -> Self {
+		Self {
+			token: None,
+			app: None,
+			api_url: default_github_api_url(),
+		}
+	}
+}
+
+fn default_github_api_url() -> String {
+	"https://api.github.com".to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct GitHubApp {
+	/// The app's ID, from its settings page.
+	pub id: u64,
+	/// The PEM-encoded private key generated for the app. Relative paths are resolved from the config file's directory.
+	pub private_key_file: PathBuf,
+	/// The installation to request tokens from. If not set, it's looked up from each site's repository.
+	pub installation_id: Option<u64>,
+}
+
+/// A build published on GitHub, deployed instead of the repository's source.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GitHubBuild {
+	/// The asset with this name, from the release tagged with the deployed ref (or the latest release).
+	ReleaseAsset(String),
+	/// The artifact with this name, from the latest workflow run that built the deployed ref.
+	WorkflowArtifact(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Oidc {
+	/// The audience tokens must be issued for.
+	#[serde(default = "default_oidc_audience")]
+	pub audience: String,
+	/// The issuer tokens must come from.
+	#[serde(default = "default_oidc_issuer")]
+	pub issuer: String,
+	/// Where to fetch the issuer's signing keys from. Defaults to its well-known JWKS endpoint.
+	pub jwks_url: Option<String>,
+	/// A local JWKS file to read the signing keys from instead of fetching them.
+	pub jwks_file: Option<PathBuf>,
+}
+
+impl Oidc {
+	/// Where the signing keys come from, used to cache them.
+	pub fn jwks_source(&self) -> String {
+		self.jwks_file.as_ref().map_or_else(
+			|| {
+				self.jwks_url.clone().unwrap_or_else(|| {
+					format!("{}/.well-known/jwks", self.issuer.trim_end_matches('/'))
+				})
+			},
+			|path| path.display().to_string(),
+		)
+	}
+}
+
+fn default_oidc_audience() -> String {
+	"orbit".to_string()
+}
+
+fn default_oidc_issuer() -> String {
+	"https://token.actions.githubusercontent.com".to_string()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SiteOidc {
+	/// The repository workflows must run in. Defaults to the site's `github_repo`.
+	pub repository: Option<String>,
+	/// The git refs workflows must run for, like `refs/heads/main`. Patterns ending in `*` match any
+	/// ref starting with the given prefix. If empty, any ref is allowed.
+	#[serde(default)]
+	pub refs: Vec<String>,
+	/// The GitHub environments workflows must target. If empty, any environment is allowed.
+	#[serde(default)]
+	pub environments: Vec<String>,
+	/// The actions workflows can perform.
+	#[serde(default = "default_oidc_scopes")]
+	pub scopes: Vec<Scope>,
+}
+
+impl SiteOidc {
+	/// Whether a workflow with the given claims can access the site.
+	pub fn allows(&self, site: &Site, claims: &Claims) -> bool {
+		let repository = self.repository.as_ref().unwrap_or(&site.github_repo);
+
+		repository.eq_ignore_ascii_case(&claims.repository)
+			&& (self.refs.is_empty()
+				|| self
+					.refs
+					.iter()
+					.any(|pattern| matches(pattern, &claims.r#ref)))
+			&& (self.environments.is_empty()
+				|| claims
+					.environment
+					.as_ref()
+					.is_some_and(|environment| self.environments.contains(environment)))
+	}
+}
+
+fn default_oidc_scopes() -> Vec<Scope> {
+	vec![Scope::Deploy]
+}
+
+fn lowercase<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+	String::deserialize(deserializer).map(|s| s.to_lowercase())
+}
+
+/// Parse a list of networks in CIDR notation, treating bare addresses as single-address networks.
+fn networks<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|net| {
+			net.parse::<IpNet>()
+				.or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+				.map_err(|_| serde::de::Error::custom(format!("invalid IP network {net}")))
+		})
+		.collect()
+}
+
 #[allow(clippy::unsafe_derive_deserialize)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Site {
+	/// The name of the site, shown in logs.
 	pub name: String,
+	/// The slug the site is referred to by in the API, from its key in `sites`.
+	#[serde(skip)]
+	slug: String,
+	/// Other slugs the site can be referred to by, like the ones it had before being renamed.
+	#[serde(default)]
+	pub aliases: Vec<String>,
+	/// The directory the site is deployed to. Releases are kept in its `deployments`
+	/// directory, and `current` links to the live one.
 	pub path: PathBuf,
+	/// The GitHub repository to deploy, as `owner/repo`.
 	pub github_repo: String,
+	/// The token used to download the repository, instead of the global `github` credentials.
+	/// Can reference one of the config's encrypted `secrets`.
+	pub github_token: Option<EnvValue>,
+	/// Deploy a build published on GitHub instead of the repository's source, like
+	/// `{ release_asset = "build.tar.gz" }` or `{ workflow_artifact = "build" }`.
+	/// Deployments can ask for a different one.
+	pub build: Option<GitHubBuild>,
+	/// Extra commands to run during each deployment. They can reference secrets as `${secret:NAME}`.
 	#[serde(default)]
 	pub commands: Vec<String>,
+	/// Extra environment variables for the commands run during a deployment.
+	#[serde(default)]
+	pub env: IndexMap<String, EnvValue>,
+	/// A dotenv file to load variables from, relative to the site's path. Values in `env` take precedence.
+	pub env_file: Option<PathBuf>,
+	/// Which of the server's own environment variables are passed down to commands.
+	#[serde(default)]
+	pub inherit_env: InheritEnv,
+	/// Which GitHub Actions workflows can authenticate for this site with OIDC tokens.
+	pub oidc: Option<SiteOidc>,
+	/// Regular expressions for extra values to mask in this site's deployment logs.
+	#[serde(default)]
+	pub redact_patterns: Vec<String>,
+	/// The decrypted secrets this site references.
+	#[serde(skip)]
+	pub secrets: IndexMap<String, Secret>,
+	/// How the site's repository is accessed on GitHub.
+	#[serde(skip)]
+	pub github: Option<Credentials>,
+	#[serde(skip)]
+	redactor: Redactor,
+	/// The config file the site was defined in.
+	#[serde(skip)]
+	pub source: PathBuf,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum EnvValue {
+	Plain(String),
+	/// A reference to one of the config's encrypted `secrets`.
+	Secret {
+		secret: String,
+	},
+}
+
+/// Variables that are never passed down to commands unless explicitly allowed, since they hold Orbit's
+/// own secrets or describe the sockets systemd passed to it.
+const ORBIT_ENV: &[&str] = &[
+	"GITHUB_TOKEN",
+	"ORBIT_*",
+	"LISTEN_PID",
+	"LISTEN_FDS",
+	"LISTEN_FDNAMES",
+];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct InheritEnv {
+	/// If set, only these variables are inherited. Note that composer needs at least `PATH` and `HOME`.
+	pub allow: Option<Vec<String>>,
+	/// Variables that are never inherited.
+	#[serde(default)]
+	pub deny: Vec<String>,
+}
+
+impl InheritEnv {
+	/// Whether the server's environment variable `key` should be passed down to commands.
+	pub fn allows(&self, key: &str) -> bool {
+		if self.deny.iter().any(|pattern| matches(pattern, key)) {
+			return false;
+		}
+
+		self.allow.as_ref().map_or_else(
+			|| !ORBIT_ENV.iter().any(|pattern| matches(pattern, key)),
+			|allow| allow.iter().any(|pattern| matches(pattern, key)),
+		)
+	}
+}
+
+/// Check a directory can be written to, or created if it doesn't exist yet.
+fn check_writable(path: &Path) -> io::Result<()> {
+	let Some(existing) = path.ancestors().find(|dir| dir.exists()) else {
+		return Err(io::Error::new(io::ErrorKind::NotFound, "no parent exists"));
+	};
+
+	if !existing.is_dir() {
+		return Err(io::Error::other(format!(
+			"{} is not a directory",
+			existing.display()
+		)));
+	}
+
+	let probe = existing.join(format!(".orbit-write-check-{}", std::process::id()));
+	fs::OpenOptions::new()
+		.write(true)
+		.create_new(true)
+		.open(&probe)?;
+
+	fs::remove_file(probe)
+}
+
+/// Whether a program can be found on the given `PATH`. Paths relative to
+/// the release (like `./vendor/bin/pint`) can't be checked in advance.
+fn find_program(program: &str, search_path: &OsStr) -> bool {
+	if program.contains('/') {
+		return !Path::new(program).is_absolute() || Path::new(program).is_file();
+	}
+
+	env::split_paths(search_path).any(|dir| dir.join(program).is_file())
+}
+
+/// Whether the value matches the pattern. Patterns ending in `*` match any value starting with the given prefix.
+fn matches(pattern: &str, value: &str) -> bool {
+	pattern
+		.strip_suffix('*')
+		.map_or(pattern == value, |prefix| value.starts_with(prefix))
 }
 
 impl Site {
-	pub fn deploy(self, r#ref: Option<String>) -> Deployer {
-		Deployer::from_site(self, r#ref)
+	pub fn slug(&self) -> String {
+		self.slug.clone()
+	}
+
+	/// Every problem with the site's settings.
+	fn errors(&self) -> Vec<String> {
+		let mut errors = vec![];
+
+		if !self.github_repo.contains('/') {
+			errors.push(format!(
+				"Invalid github_repo for site {}. Must be in the format of owner/repo",
+				self.name
+			));
+		}
+
+		if !self.path.is_absolute() {
+			errors.push(format!(
+				"The path of site {} must be absolute, got {}",
+				self.name,
+				self.path.display()
+			));
+		}
+
+		for command in &self.commands {
+			if shlex::split(command).is_none_or(|argv| argv.is_empty()) {
+				errors.push(format!(
+					"Invalid command `{command}` for site {}",
+					self.name
+				));
+			}
+		}
+
+		errors
+	}
+
+	/// Problems with this machine's setup for the site, like a missing program or an unwritable path.
+	fn warnings(&self) -> Vec<String> {
+		let mut warnings = vec![];
+
+		if self.path.is_absolute() {
+			if let Err(e) = check_writable(&self.path) {
+				warnings.push(format!(
+					"The path of site {} isn't writable: {e}",
+					self.name
+				));
+			}
+		}
+
+		let Some(search_path) = self.search_path() else {
+			warnings.push(format!(
+				"Site {} doesn't pass PATH to its commands, so they can't find `php` or `composer`. Add it to `inherit_env.allow` or `env`.",
+				self.name
+			));
+			return warnings;
+		};
+
+		let mut programs = vec!["php".to_string(), "composer".to_string()];
+		for argv in self
+			.commands
+			.iter()
+			.filter_map(|command| shlex::split(command))
+		{
+			if let Some(program) = argv.into_iter().next() {
+				if !programs.contains(&program) {
+					programs.push(program);
+				}
+			}
+		}
+
+		for program in programs {
+			if !find_program(&program, &search_path) {
+				warnings.push(format!(
+					"`{program}`, used by site {}, was not found on its PATH",
+					self.name
+				));
+			}
+		}
+
+		warnings
+	}
+
+	/// The `PATH` the site's commands are looked up in.
+	fn search_path(&self) -> Option<OsString> {
+		match self.env.get("PATH") {
+			Some(EnvValue::Plain(path)) => Some(path.into()),
+			Some(EnvValue::Secret { .. }) => None,
+			None => self
+				.inherit_env
+				.allows("PATH")
+				.then(|| env::var_os("PATH"))
+				.flatten(),
+		}
+	}
+
+	/// The site's slug, followed by its aliases.
+	pub fn slugs(&self) -> impl Iterator<Item = String> + '_ {
+		std::iter::once(self.slug()).chain(self.aliases.iter().cloned())
+	}
+
+	/// Start a deployment of this site, triggered by the token with the given name.
+	pub fn deploy(
+		self,
+		r#ref: Option<String>,
+		triggered_by: String,
+		tokens: Arc<TokenCache>,
+	) -> Deployer {
+		Deployer::from_site(self, r#ref, triggered_by, tokens)
+	}
+
+	/// Build the environment for the commands run for this site, from the inherited
+	/// server variables, the site's `env_file` and its `env` table (in increasing priority).
+	pub fn command_env(&self) -> io::Result<Vec<(OsString, OsString)>> {
+		let mut vars: Vec<(OsString, OsString)> = env::vars_os()
+			.filter(|(key, _)| self.inherit_env.allows(&key.to_string_lossy()))
+			.collect();
+
+		if let Some(env_file) = &self.env_file {
+			for item in
+				dotenvy::from_path_iter(self.path.join(env_file)).map_err(io::Error::other)?
+			{
+				let (key, value) = item.map_err(io::Error::other)?;
+				vars.push((key.into(), value.into()));
+			}
+		}
+
+		for (key, value) in &self.env {
+			let value = match value {
+				EnvValue::Plain(value) => value,
+				EnvValue::Secret { secret } => self.secret(secret)?.expose(),
+			};
+
+			vars.push((key.into(), value.into()));
+		}
+
+		Ok(vars)
+	}
+
+	fn secret(&self, name: &str) -> io::Result<&Secret> {
+		self.secrets.get(name).ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::NotFound,
+				format!("Secret {name} has not been unlocked"),
+			)
+		})
+	}
+
+	/// The names of the secrets referenced from the site's `env`, `commands` and `github_token`.
+	fn secret_references(&self) -> impl Iterator<Item = &str> {
+		self.env
+			.values()
+			.chain(&self.github_token)
+			.filter_map(|value| match value {
+				EnvValue::Secret { secret } => Some(secret.as_str()),
+				EnvValue::Plain(_) => None,
+			})
+			.chain(
+				self.commands
+					.iter()
+					.flat_map(|command| secrets::references(command)),
+			)
+	}
+
+	/// A redactor for the output of commands run for this site, which also masks the
+	/// secret-looking values from its `.env` and `env_file`.
+	pub fn redactor(&self) -> Redactor {
+		let mut redactor = self.redactor.clone();
+
+		redactor.add_env_file(&self.path.join(".env"));
+		if let Some(env_file) = &self.env_file {
+			redactor.add_env_file(&self.path.join(env_file));
+		}
+
+		redactor
 	}
 }
@@ -1,26 +1,72 @@
-use async_fn_stream::try_fn_stream;
+use async_fn_stream::{try_fn_stream, TryStreamEmitter};
+use axum::body::{Body, BodyDataStream};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use flate2::read::GzDecoder;
 use futures_util::{Stream, StreamExt, TryStreamExt};
 use http::header;
-use orbit_types::{Log, Progress, Stage};
+use orbit_types::{
+	DeploymentFailure, DownloadProgress, Log, Progress, Release, RunningDeployment, SiteStatus,
+	SiteSummary, Stage,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use shlex::Shlex;
-use std::{env, fs, path::PathBuf};
-use tokio::process::Command;
+use std::{
+	collections::HashMap,
+	ffi::{OsStr, OsString},
+	fs,
+	io::{self, Read, Seek},
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, process::Command, sync::mpsc};
 use uuid::Uuid;
 
 use crate::{
-	config::Site,
-	misc::{spawn_with_logs, untar_to},
+	config::{GitHubBuild, Site},
+	github::{self, TokenCache},
+	misc::{dir_size, site_command, spawn_with_logs, untar_to, unzip_to, ChannelReader},
+	redact::Redactor,
+	secrets::{self, Secret},
 };
 
+/// How many downloaded chunks can be waiting to be extracted before the download is paused.
+const DOWNLOAD_BUFFER: usize = 16;
+
+/// How often download progress is reported.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The file in each release recording how it was deployed.
+const METADATA_FILE: &str = ".orbit.json";
+
+/// The file in the site's directory recording its most recent failed deployment.
+const FAILURE_FILE: &str = ".orbit-failure.json";
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
 	#[error("Failed to bootstrap the project.")]
 	Bootstrap(std::io::Error),
 
+	#[error("Failed to authenticate with GitHub.")]
+	Authenticate(anyhow::Error),
+
 	#[error("Failed to clone the repository.")]
 	Download(#[from] reqwest::Error),
 
+	#[error("Failed to receive the artifact.")]
+	Upload(std::io::Error),
+
+	#[error("The artifact doesn't match its checksum.")]
+	Checksum,
+
+	#[error("There's no checksum to verify the build against.")]
+	Unverifiable,
+
+	#[error("Failed to find the build to deploy.")]
+	MissingBuild(String),
+
 	#[error("Failed to extract the repository contents.")]
 	Extraction(std::io::Error),
 
@@ -48,7 +94,10 @@ impl From<Error> for orbit_types::Error {
 		match value {
 			Error::Cleanup(_) => Self::Cleanup,
 			Error::Publish(_) => Self::Publish,
-			Error::Download(_) => Self::Download,
+			Error::Authenticate(_) | Error::Download(_) => Self::Download,
+			Error::Upload(_) => Self::Upload,
+			Error::Checksum | Error::Unverifiable => Self::Checksum,
+			Error::MissingBuild(_) => Self::MissingBuild,
 			Error::Optimize(_) => Self::Optimize,
 			Error::Bootstrap(_) => Self::Bootstrap,
 			Error::Configure(_) => Self::Configure,
@@ -65,46 +114,191 @@ impl From<Error> for orbit_types::ErrorResponse {
 	}
 }
 
+/// What a release records about itself.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Metadata {
+	r#ref: Option<String>,
+	commit: Option<String>,
+	artifact: Option<String>,
+	triggered_by: String,
+	deployed_at: Option<DateTime<Utc>>,
+}
+
+/// A build uploaded to be deployed as is, instead of downloading the repository.
+pub struct Artifact {
+	// deployers are shared across awaits, so the body (which isn't `Sync`) is kept behind a lock
+	body: Mutex<BodyDataStream>,
+	size: Option<u64>,
+	checksum: String,
+	max_size: u64,
+}
+
+impl Artifact {
+	/// An artifact read from a request body, which must match the given SHA-256 checksum
+	/// (as lowercase hex) and be at most `max_size` bytes long.
+	pub fn new(body: Body, size: Option<u64>, checksum: String, max_size: u64) -> Self {
+		Self {
+			size,
+			checksum,
+			max_size,
+			body: Mutex::new(body.into_data_stream()),
+		}
+	}
+}
+
+/// A build published on GitHub, deployed instead of the repository's source.
+#[derive(Debug)]
+pub struct Build {
+	source: GitHubBuild,
+	run_id: Option<u64>,
+	checksum: Option<String>,
+	max_size: u64,
+}
+
+impl Build {
+	/// A release asset or workflow artifact, taken from the given workflow run if set. Its digest
+	/// (as published by GitHub, or the given SHA-256 checksum) is verified, so builds without either are refused.
+	pub const fn new(
+		source: GitHubBuild,
+		run_id: Option<u64>,
+		checksum: Option<String>,
+		max_size: u64,
+	) -> Self {
+		Self {
+			source,
+			run_id,
+			checksum,
+			max_size,
+		}
+	}
+}
+
 pub struct Deployer {
 	site: Site,
 	deployment_id: Uuid,
-	github_token: String,
+	tokens: Arc<TokenCache>,
 	r#ref: Option<String>,
+	commit: Option<String>,
+	artifact: Option<Artifact>,
+	build: Option<Build>,
+	checksum: Option<String>,
 	client: reqwest::Client,
+	env: Vec<(OsString, OsString)>,
+	redactor: Redactor,
+	triggered_by: String,
+	started_at: DateTime<Utc>,
+	tracked: Option<Tracked>,
 }
 
 impl Deployer {
-	pub fn from_site(site: Site, r#ref: Option<String>) -> Self {
-		// we unwrap here since Config::validate errors ealier if GITHUB_TOKEN is not set
-		let github_token = env::var("GITHUB_TOKEN").expect("GITHUB_TOKEN is not set");
-
+	pub fn from_site(
+		site: Site,
+		r#ref: Option<String>,
+		triggered_by: String,
+		tokens: Arc<TokenCache>,
+	) -> Self {
 		Self {
+			redactor: site.redactor(),
 			site,
+			tokens,
+			triggered_by,
 			r#ref,
-			github_token,
+			commit: None,
+			artifact: None,
+			build: None,
+			checksum: None,
+			env: Vec::new(),
+			started_at: Utc::now(),
+			tracked: None,
 			deployment_id: Uuid::now_v7(),
-			client: reqwest::Client::builder()
-				.user_agent("orbit-deployer")
-				.build()
-				.unwrap(),
+			client: github::client(),
 		}
 	}
 
+	/// The deployment, as reported while it's running.
+	pub fn info(&self) -> RunningDeployment {
+		RunningDeployment {
+			r#ref: self.r#ref.clone(),
+			started_at: self.started_at,
+			id: self.deployment_id.to_string(),
+			triggered_by: self.triggered_by.clone(),
+		}
+	}
+
+	/// Deploy an uploaded artifact instead of downloading the repository.
+	#[must_use]
+	pub fn with_artifact(mut self, artifact: Artifact) -> Self {
+		self.artifact = Some(artifact);
+		self
+	}
+
+	/// Deploy a build published on GitHub instead of the repository's source.
+	#[must_use]
+	pub fn with_build(mut self, build: Build) -> Self {
+		self.build = Some(build);
+		self
+	}
+
+	/// Report the deployment as running on the site until it finishes.
+	#[must_use]
+	pub fn tracked_by(mut self, tracker: &Arc<Tracker>) -> Self {
+		self.tracked = Some(tracker.track(&self.site, self.info()));
+		self
+	}
+
+	/// Run the deployment, recording it as the site's last failure if it fails.
 	pub fn stream(self) -> impl Stream<Item = std::result::Result<Progress, Error>> {
+		let site_path = self.site.path.clone();
+		let info = self.info();
+
+		self.run().inspect_err(move |e| {
+			let failure = DeploymentFailure {
+				id: info.id.clone(),
+				r#ref: info.r#ref.clone(),
+				failed_at: Utc::now(),
+				error: e.to_string(),
+				triggered_by: info.triggered_by.clone(),
+			};
+
+			if let Err(e) = write_json(&site_path.join(FAILURE_FILE), &failure) {
+				tracing::error!(e = ?e, "Failed to record deployment failure");
+			}
+		})
+	}
+
+	fn run(mut self) -> impl Stream<Item = std::result::Result<Progress, Error>> {
 		try_fn_stream(|stream| async move {
+			// reported as running for as long as this stream is alive
+			let _tracked = self.tracked.take();
+
+			tracing::info!(
+				site = self.site.name,
+				deployment = %self.deployment_id,
+				token = self.triggered_by,
+				"Starting deployment"
+			);
 			stream.emit(Stage::Starting.into()).await;
 
 			self.bootstrap_site()?;
-			self.download_repo().await?;
+			if let Some(artifact) = self.artifact.take() {
+				self.receive_artifact(artifact, &stream).await?;
+			} else if let Some(build) = self.build.take() {
+				self.download_build(build, &stream).await?;
+			} else {
+				self.download_repo(&stream).await?;
+			}
 
 			stream.emit(Stage::Downloaded.into()).await;
 
 			self.configure_deployment()?;
+			self.env = self.site.command_env().map_err(Error::Configure)?;
 
 			if self.should_install_deps() {
 				self.install_deps()
 					.try_for_each(|log| async {
-						stream.emit(Progress::Log(log)).await;
+						stream
+							.emit(Progress::Log(self.redactor.redact_log(log)))
+							.await;
 						Ok(())
 					})
 					.await?;
@@ -114,14 +308,18 @@ impl Deployer {
 
 			self.run_commands()
 				.try_for_each(|log| async {
-					stream.emit(Progress::Log(log)).await;
+					stream
+						.emit(Progress::Log(self.redactor.redact_log(log)))
+						.await;
 					Ok(())
 				})
 				.await?;
 
 			self.optimize_deployment()
 				.try_for_each(|log| async {
-					stream.emit(Progress::Log(log)).await;
+					stream
+						.emit(Progress::Log(self.redactor.redact_log(log)))
+						.await;
 					Ok(())
 				})
 				.await?;
@@ -130,7 +328,9 @@ impl Deployer {
 
 			self.migrate()
 				.try_for_each(|log| async {
-					stream.emit(Progress::Log(log)).await;
+					stream
+						.emit(Progress::Log(self.redactor.redact_log(log)))
+						.await;
 					Ok(())
 				})
 				.await?;
@@ -167,37 +367,312 @@ impl Deployer {
 		Ok(())
 	}
 
-	async fn download_repo(&self) -> Result<(), Error> {
-		// we unwrap here since Config::validate errors ealier if `github_repo` does not cointain a `/`
-		let (owner, repo) = self.site.github_repo.split_once('/').unwrap();
+	async fn download_repo(
+		&mut self,
+		stream: &TryStreamEmitter<Progress, Error>,
+	) -> Result<(), Error> {
+		let (api, token) = self.github_token().await?;
 
-		let tarball = self
+		// resolve the ref first, so we know exactly which commit is being deployed
+		let commit = self
+			.resolve_commit(&api, &token, self.r#ref.as_deref().unwrap_or("HEAD"))
+			.await?;
+
+		let mut response = self
 			.client
-			.get(format!(
-				"https://api.github.com/repos/{owner}/{repo}/tarball/{}",
-				self.r#ref
-					.as_ref()
-					.map_or(String::new(), |r#ref| format!("/{ref}"))
-			))
-			.header(
-				header::AUTHORIZATION,
-				format!("Bearer {}", self.github_token),
+			.get(format!("{api}/tarball/{commit}"))
+			.bearer_auth(token.expose())
+			.send()
+			.await?
+			.error_for_status()?;
+
+		// extract the tarball on a blocking thread as it arrives, so it's never held in memory
+		let (chunks, receiver) = mpsc::channel(DOWNLOAD_BUFFER);
+		let path = self.get_path();
+		let extraction = tokio::task::spawn_blocking(move || {
+			untar_to(
+				tar::Archive::new(GzDecoder::new(ChannelReader::new(receiver))),
+				&path,
+				1,
 			)
+		});
+
+		let mut progress = DownloadProgress {
+			downloaded: 0,
+			total: response.content_length(),
+		};
+		let mut last_reported = Instant::now();
+		let download = loop {
+			match response.chunk().await {
+				Ok(Some(chunk)) => {
+					progress.downloaded += chunk.len() as u64;
+					if last_reported.elapsed() >= PROGRESS_INTERVAL {
+						stream.emit(Progress::Download(progress)).await;
+						last_reported = Instant::now();
+					}
+
+					// extraction stopped early, so its error is reported below
+					if chunks.send(Ok(chunk)).await.is_err() {
+						break Ok(());
+					}
+				},
+				Ok(None) => break Ok(()),
+				Err(e) => {
+					_ = chunks
+						.send(Err(io::Error::other("the download was interrupted")))
+						.await;
+					break Err(e);
+				},
+			}
+		};
+		drop(chunks);
+
+		let extracted = extraction.await.map_err(io::Error::other);
+		download?;
+		stream.emit(Progress::Download(progress)).await;
+		extracted
+			.and_then(|result| result)
+			.map_err(Error::Extraction)?;
+
+		self.commit = Some(commit);
+		self.write_metadata(None).map_err(Error::Extraction)?;
+
+		Ok(())
+	}
+
+	/// A token for the site's repository, along with the repository's API URL.
+	async fn github_token(&mut self) -> Result<(String, Secret), Error> {
+		// we unwrap here since Config::validate errors ealier if the site has no GitHub credentials
+		let credentials = self.site.github.clone().unwrap();
+
+		let token = credentials
+			.access_token(&self.client, &self.tokens, &self.site.github_repo)
+			.await
+			.map_err(Error::Authenticate)?;
+		self.redactor.add_value(token.expose());
+
+		Ok((
+			format!("{}/repos/{}", credentials.api_url, self.site.github_repo),
+			token,
+		))
+	}
+
+	async fn resolve_commit(
+		&self,
+		api: &str,
+		token: &Secret,
+		r#ref: &str,
+	) -> Result<String, Error> {
+		Ok(self
+			.client
+			.get(format!("{api}/commits/{ref}"))
+			.bearer_auth(token.expose())
+			.header(header::ACCEPT, "application/vnd.github.sha")
 			.send()
 			.await?
 			.error_for_status()?
-			.bytes()
-			.await?;
+			.text()
+			.await?)
+	}
 
-		untar_to(
-			tar::Archive::new(GzDecoder::new(tarball.as_ref())),
-			&self.get_path(),
+	async fn get_json<T: serde::de::DeserializeOwned>(
+		&self,
+		url: &str,
+		token: &Secret,
+		query: &[(&str, &str)],
+	) -> Result<T, Error> {
+		Ok(self
+			.client
+			.get(url)
+			.query(query)
+			.bearer_auth(token.expose())
+			.header(header::ACCEPT, "application/vnd.github+json")
+			.send()
+			.await?
+			.error_for_status()?
+			.json()
+			.await?)
+	}
+
+	async fn download_build(
+		&mut self,
+		build: Build,
+		stream: &TryStreamEmitter<Progress, Error>,
+	) -> Result<(), Error> {
+		let (api, token) = self.github_token().await?;
+
+		let (commit, url, digest) = match &build.source {
+			GitHubBuild::ReleaseAsset(name) => {
+				let release: GitHubRelease = match &self.r#ref {
+					Some(tag) => self
+						.get_json(&format!("{api}/releases/tags/{tag}"), &token, &[])
+						.await
+						.map_err(not_found(|| format!("there's no release tagged {tag}")))?,
+					None => self
+						.get_json(&format!("{api}/releases/latest"), &token, &[])
+						.await
+						.map_err(not_found(|| "there are no releases".to_string()))?,
+				};
+
+				let asset = release
+					.assets
+					.into_iter()
+					.find(|asset| asset.name == *name)
+					.ok_or_else(|| {
+						Error::MissingBuild(format!(
+							"release {} has no asset named {name}",
+							release.tag_name
+						))
+					})?;
+				let commit = self.resolve_commit(&api, &token, &release.tag_name).await?;
+
+				(commit, asset.url, asset.digest)
+			},
+			GitHubBuild::WorkflowArtifact(name) => {
+				let artifact = if let Some(run_id) = build.run_id {
+					self.get_json::<GitHubArtifacts>(
+						&format!("{api}/actions/runs/{run_id}/artifacts"),
+						&token,
+						&[("name", name)],
+					)
+					.await
+					.map_err(not_found(|| format!("there's no workflow run {run_id}")))?
+					.artifacts
+					.into_iter()
+					.find(|artifact| !artifact.expired)
+					.ok_or_else(|| {
+						Error::MissingBuild(format!("run {run_id} has no artifact named {name}"))
+					})?
+				} else {
+					let commit = self
+						.resolve_commit(&api, &token, self.r#ref.as_deref().unwrap_or("HEAD"))
+						.await?;
+
+					// artifacts are listed newest first
+					self.get_json::<GitHubArtifacts>(
+						&format!("{api}/actions/artifacts"),
+						&token,
+						&[("name", name), ("per_page", "100")],
+					)
+					.await?
+					.artifacts
+					.into_iter()
+					.find(|artifact| !artifact.expired && artifact.workflow_run.head_sha == commit)
+					.ok_or_else(|| {
+						Error::MissingBuild(format!(
+							"no workflow run built an artifact named {name} from {commit}"
+						))
+					})?
+				};
+
+				(
+					artifact.workflow_run.head_sha,
+					artifact.archive_download_url,
+					artifact.digest,
+				)
+			},
+		};
+
+		// GitHub publishes the digest of newer uploads, and either it or the one we were given
+		// (or both, which then have to agree) is what the download is checked against
+		let digest =
+			digest.and_then(|digest| digest.strip_prefix("sha256:").map(str::to_lowercase));
+		let checksum = match (digest, build.checksum) {
+			(Some(digest), Some(checksum)) if digest != checksum => return Err(Error::Checksum),
+			(Some(checksum), _) | (None, Some(checksum)) => checksum,
+			(None, None) => return Err(Error::Unverifiable),
+		};
+
+		// assets and artifacts redirect to storage elsewhere, which the token isn't forwarded to
+		let response = self
+			.client
+			.get(url)
+			.bearer_auth(token.expose())
+			.header(header::ACCEPT, "application/octet-stream")
+			.send()
+			.await?
+			.error_for_status()?;
+
+		self.commit = Some(commit);
+		let size = response.content_length();
+		self.receive(
+			response.bytes_stream(),
+			size,
+			build.max_size,
+			checksum,
+			stream,
 		)
-		.map_err(Error::Extraction)?;
+		.await
+	}
+
+	async fn receive_artifact(
+		&mut self,
+		artifact: Artifact,
+		stream: &TryStreamEmitter<Progress, Error>,
+	) -> Result<(), Error> {
+		let Artifact {
+			body,
+			size,
+			checksum,
+			max_size,
+		} = artifact;
+
+		self.receive(body.into_inner().unwrap(), size, max_size, checksum, stream)
+			.await
+	}
+
+	/// Save an artifact as it arrives, and extract it into the release once it's verified.
+	async fn receive<E: std::error::Error + Send + Sync + 'static>(
+		&mut self,
+		body: impl Stream<Item = Result<Bytes, E>> + Send,
+		size: Option<u64>,
+		max_size: u64,
+		checksum: String,
+		stream: &TryStreamEmitter<Progress, Error>,
+	) -> Result<(), Error> {
+		// kept outside the release, so it's never deployed even if extracting it fails
+		let file_path = self
+			.site
+			.path
+			.join(format!(".orbit-artifact-{}", self.deployment_id));
+
+		let received = save_artifact(body, size, max_size, &file_path, stream).await;
+		let extracted = match received {
+			Ok(received) if received == checksum => {
+				let path = self.get_path();
+				let file_path = file_path.clone();
+
+				tokio::task::spawn_blocking(move || extract_artifact(&file_path, &path))
+					.await
+					.map_err(io::Error::other)
+					.and_then(|result| result)
+					.map_err(Error::Extraction)
+			},
+			Ok(_) => Err(Error::Checksum),
+			Err(e) => Err(e),
+		};
+
+		_ = fs::remove_file(&file_path);
+		extracted?;
+
+		self.checksum = Some(checksum);
+		self.write_metadata(None).map_err(Error::Extraction)?;
 
 		Ok(())
 	}
 
+	fn write_metadata(&self, deployed_at: Option<DateTime<Utc>>) -> io::Result<()> {
+		let metadata = Metadata {
+			deployed_at,
+			r#ref: self.r#ref.clone(),
+			commit: self.commit.clone(),
+			artifact: self.checksum.clone(),
+			triggered_by: self.triggered_by.clone(),
+		};
+
+		write_json(&self.get_path().join(METADATA_FILE), &metadata)
+	}
+
 	fn configure_deployment(&self) -> Result<(), Error> {
 		let env_path = self.site.path.join(".env");
 		if env_path.exists() {
@@ -215,15 +690,19 @@ impl Deployer {
 		Ok(())
 	}
 
+	/// Create a command that runs inside the current deployment, with the site's environment.
+	fn command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+		site_command(program, &self.env, &self.get_path())
+	}
+
 	fn install_deps(&self) -> impl Stream<Item = Result<Log, Error>> {
 		spawn_with_logs(
-			Command::new("composer")
+			self.command("composer")
 				.arg("install")
 				.arg("--no-dev")
 				.arg("--prefer-dist")
 				.arg("--no-interaction")
-				.arg("--optimize-autoloader")
-				.current_dir(self.get_path()),
+				.arg("--optimize-autoloader"),
 		)
 		.map_err(Error::InstallDeps)
 	}
@@ -232,12 +711,10 @@ impl Deployer {
 		let mut streams = vec![];
 
 		for command in &self.site.commands {
-			let mut argv = Shlex::new(command);
+			let mut argv = Shlex::new(command).map(|arg| secrets::expand(&arg, &self.site.secrets));
 
 			streams.push(spawn_with_logs(
-				Command::new(argv.next().unwrap())
-					.args(argv)
-					.current_dir(self.get_path()),
+				self.command(argv.next().unwrap()).args(argv),
 			));
 		}
 
@@ -248,26 +725,23 @@ impl Deployer {
 
 	fn migrate(&self) -> impl Stream<Item = Result<Log, Error>> {
 		spawn_with_logs(
-			Command::new("php")
+			self.command("php")
 				.arg("artisan")
 				.arg("migrate")
-				.arg("--force")
-				.current_dir(self.get_path()),
+				.arg("--force"),
 		)
 		.map_err(Error::InstallDeps)
 	}
 
 	fn optimize_deployment(&self) -> impl Stream<Item = Result<Log, Error>> {
-		spawn_with_logs(
-			Command::new("php")
-				.arg("artisan")
-				.arg("optimize")
-				.current_dir(self.get_path()),
-		)
-		.map_err(Error::Optimize)
+		spawn_with_logs(self.command("php").arg("artisan").arg("optimize")).map_err(Error::Optimize)
 	}
 
 	fn set_live(&self) -> Result<(), Error> {
+		// written first, so a failure can't leave the site live on a deployment reported as failed
+		self.write_metadata(Some(Utc::now()))
+			.map_err(Error::Publish)?;
+
 		let current_deployment = self.site.path.join("current");
 		if current_deployment.exists() {
 			fs::remove_file(&current_deployment).map_err(Error::Publish)?;
@@ -321,3 +795,306 @@ impl Deployer {
 		path.join("composer.json").exists() && !path.join("vendor").exists()
 	}
 }
+
+#[derive(Deserialize)]
+struct GitHubRelease {
+	tag_name: String,
+	assets: Vec<GitHubAsset>,
+}
+
+#[derive(Deserialize)]
+struct GitHubAsset {
+	name: String,
+	url: String,
+	digest: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubArtifacts {
+	artifacts: Vec<GitHubArtifact>,
+}
+
+#[derive(Deserialize)]
+struct GitHubArtifact {
+	expired: bool,
+	archive_download_url: String,
+	digest: Option<String>,
+	workflow_run: GitHubWorkflowRun,
+}
+
+#[derive(Deserialize)]
+struct GitHubWorkflowRun {
+	head_sha: String,
+}
+
+/// Read what's deployed on a site from disk.
+pub fn status(site: &Site, running: Vec<RunningDeployment>) -> io::Result<SiteStatus> {
+	let mut releases = vec![];
+
+	match fs::read_dir(site.path.join("deployments")) {
+		Ok(entries) => {
+			for entry in entries {
+				let entry = entry?;
+				if !entry.file_type()?.is_dir() {
+					continue;
+				}
+
+				let path = entry.path();
+				let disk_usage = match dir_size(&path) {
+					Ok(bytes) => bytes,
+					// the release was cleaned up while we were looking at it
+					Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+					Err(e) => return Err(e),
+				};
+				let metadata = read_json::<Metadata>(&path.join(METADATA_FILE)).unwrap_or_default();
+
+				releases.push(Release {
+					size: disk_usage,
+					r#ref: metadata.r#ref,
+					commit: metadata.commit,
+					artifact: metadata.artifact,
+					deployed_at: metadata.deployed_at,
+					id: entry.file_name().to_string_lossy().into_owned(),
+				});
+			}
+		},
+		Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+		Err(e) => return Err(e),
+	}
+
+	// deployment ids are v7 UUIDs, which sort by creation time
+	releases.sort_by(|a, b| b.id.cmp(&a.id));
+
+	let live = fs::read_link(site.path.join("current"))
+		.ok()
+		.and_then(|target| {
+			target
+				.file_name()
+				.map(|id| id.to_string_lossy().into_owned())
+		})
+		.and_then(|id| releases.iter().find(|release| release.id == id).cloned());
+
+	Ok(SiteStatus {
+		live,
+		running,
+		releases,
+		name: site.name.clone(),
+		slug: site.slug(),
+		last_failure: read_json(&site.path.join(FAILURE_FILE)),
+	})
+}
+
+/// Summarize a site and its live deployment.
+pub fn summary(site: &Site) -> SiteSummary {
+	let live = live_id(site);
+	let metadata = live
+		.as_ref()
+		.and_then(|id| {
+			read_json::<Metadata>(&site.path.join("deployments").join(id).join(METADATA_FILE))
+		})
+		.unwrap_or_default();
+
+	SiteSummary {
+		live,
+		slug: site.slug(),
+		name: site.name.clone(),
+		commit: metadata.commit,
+		deployed_at: metadata.deployed_at,
+		github_repo: site.github_repo.clone(),
+	}
+}
+
+/// The id of the deployment the site's `current` symlink points to.
+fn live_id(site: &Site) -> Option<String> {
+	let target = fs::read_link(site.path.join("current")).ok()?;
+
+	Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The deployments in progress on each site.
+#[derive(Debug, Default)]
+pub struct Tracker(Mutex<HashMap<String, Vec<RunningDeployment>>>);
+
+impl Tracker {
+	pub fn running(&self, site: &Site) -> Vec<RunningDeployment> {
+		self.0
+			.lock()
+			.unwrap()
+			.get(&site.slug())
+			.cloned()
+			.unwrap_or_default()
+	}
+
+	/// Mark a deployment as running until the returned guard is dropped.
+	pub fn track(self: &Arc<Self>, site: &Site, deployment: RunningDeployment) -> Tracked {
+		let tracked = Tracked {
+			slug: site.slug(),
+			tracker: self.clone(),
+			id: deployment.id.clone(),
+		};
+
+		self.0
+			.lock()
+			.unwrap()
+			.entry(tracked.slug.clone())
+			.or_default()
+			.push(deployment);
+
+		tracked
+	}
+}
+
+/// A running deployment, which stops being reported as such when dropped.
+#[derive(Debug)]
+pub struct Tracked {
+	id: String,
+	slug: String,
+	tracker: Arc<Tracker>,
+}
+
+impl Drop for Tracked {
+	fn drop(&mut self) {
+		let mut running = self.tracker.0.lock().unwrap();
+
+		if let Some(deployments) = running.get_mut(&self.slug) {
+			deployments.retain(|deployment| deployment.id != self.id);
+			if deployments.is_empty() {
+				running.remove(&self.slug);
+			}
+		}
+	}
+}
+
+/// Whether a ref or tag to deploy is a valid Git ref name, which also makes it safe to put in
+/// GitHub API paths. Its components can't be empty or start with a dot, and it can't contain `..`,
+/// whitespace, the characters Git reserves or anything else that would change the URL, like `#` or `%`.
+pub fn is_valid_ref(r#ref: &str) -> bool {
+	!r#ref.contains("..")
+		&& !r#ref.contains("@{")
+		&& !r#ref.ends_with('.')
+		&& r#ref
+			.split('/')
+			.all(|part| !part.is_empty() && !part.starts_with('.'))
+		&& !r#ref
+			.chars()
+			.any(|c| c.is_control() || c.is_whitespace() || "~^:?*[\\#%".contains(c))
+}
+
+/// Report a GitHub API call that was answered with a 404 as a missing build, described by `missing`.
+fn not_found(missing: impl FnOnce() -> String) -> impl FnOnce(Error) -> Error {
+	move |e| match e {
+		Error::Download(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+			Error::MissingBuild(missing())
+		},
+		e => e,
+	}
+}
+
+/// Write an artifact to `path` as it arrives, returning its SHA-256 checksum.
+async fn save_artifact<E: std::error::Error + Send + Sync + 'static>(
+	body: impl Stream<Item = Result<Bytes, E>> + Send,
+	size: Option<u64>,
+	max_size: u64,
+	path: &Path,
+	stream: &TryStreamEmitter<Progress, Error>,
+) -> Result<String, Error> {
+	let mut file = tokio::fs::File::create(path).await.map_err(Error::Upload)?;
+	let mut hasher = Sha256::new();
+	let mut body = std::pin::pin!(body);
+
+	let mut progress = DownloadProgress {
+		downloaded: 0,
+		total: size,
+	};
+	let mut last_reported = Instant::now();
+	while let Some(chunk) = body.next().await {
+		let chunk = chunk.map_err(|e| Error::Upload(io::Error::other(e)))?;
+
+		progress.downloaded += chunk.len() as u64;
+		if progress.downloaded > max_size {
+			return Err(Error::Upload(io::Error::other(format!(
+				"the artifact is larger than the limit of {max_size} bytes"
+			))));
+		}
+
+		hasher.update(&chunk);
+		file.write_all(&chunk).await.map_err(Error::Upload)?;
+
+		if last_reported.elapsed() >= PROGRESS_INTERVAL {
+			stream.emit(Progress::Download(progress)).await;
+			last_reported = Instant::now();
+		}
+	}
+
+	file.flush().await.map_err(Error::Upload)?;
+	stream.emit(Progress::Download(progress)).await;
+
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Extract an artifact into `path`, telling gzipped tarballs and zip archives apart by their
+/// first bytes. Unlike repository tarballs, their entries aren't wrapped in a directory.
+fn extract_artifact(file_path: &Path, path: &Path) -> io::Result<()> {
+	let mut file = fs::File::open(file_path)?;
+	let mut magic = [0; 2];
+	file.read_exact(&mut magic)?;
+	file.rewind()?;
+
+	match magic {
+		[0x1f, 0x8b] => untar_to(tar::Archive::new(GzDecoder::new(file)), path, 0),
+		[b'P', b'K'] => unzip_to(file, path),
+		_ => Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			"the artifact is neither a gzipped tarball nor a zip archive",
+		)),
+	}
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Option<T> {
+	let contents = fs::read(path).ok()?;
+
+	serde_json::from_slice(&contents).ok()
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+	fs::write(path, serde_json::to_vec_pretty(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn accepts_branches_tags_and_commits() {
+		for r#ref in [
+			"main",
+			"HEAD",
+			"feature/new-thing",
+			"v1.2.3",
+			"refs/tags/v1",
+			"0a1b2c3",
+		] {
+			assert!(is_valid_ref(r#ref), "{ref}");
+		}
+	}
+
+	#[test]
+	fn rejects_refs_that_would_change_the_url() {
+		for r#ref in [
+			"",
+			"..",
+			"../../../user",
+			"main/../../hooks",
+			"./main",
+			"main?per_page=1",
+			"main#fragment",
+			"main%2F..%2Fhooks",
+			"main/",
+			"feature//x",
+			"main branch",
+			"main\n",
+		] {
+			assert!(!is_valid_ref(r#ref), "{ref:?}");
+		}
+	}
+}
@@ -0,0 +1,242 @@
+use std::{
+	collections::HashMap,
+	fs, io,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::config::Site;
+
+/// A lock on each site's `.env` file, so concurrent changes can't overwrite each other.
+#[derive(Debug, Default)]
+pub struct Locks(Mutex<HashMap<String, Arc<AsyncMutex<()>>>>);
+
+impl Locks {
+	/// Wait until no one else is changing the site's `.env` file, holding it until the guard is dropped.
+	pub async fn lock(&self, site: &Site) -> OwnedMutexGuard<()> {
+		let lock = self
+			.0
+			.lock()
+			.unwrap()
+			.entry(site.slug())
+			.or_default()
+			.clone();
+
+		lock.lock_owned().await
+	}
+}
+
+/// A `.env` file that can be edited without losing its comments, blank lines or ordering.
+#[derive(Debug)]
+pub struct DotEnv {
+	path: PathBuf,
+	entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+enum Entry {
+	/// A variable definition, kept verbatim (it may span several lines).
+	Var { key: String, raw: String },
+	/// A comment, blank line or anything else we don't touch.
+	Other(String),
+}
+
+impl DotEnv {
+	/// Load the file at the given path. A missing file is treated as empty.
+	pub fn load(path: PathBuf) -> io::Result<Self> {
+		let contents = match fs::read_to_string(&path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+
+		// make sure we can read the file the same way Laravel (and dotenvy) would before touching it
+		if !contents.is_empty() {
+			dotenvy::from_read_iter(contents.as_bytes())
+				.try_for_each(|item| item.map(|_| ()))
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		}
+
+		Ok(Self {
+			path,
+			entries: parse(&contents),
+		})
+	}
+
+	/// The names of the variables defined in the file, in order.
+	pub fn keys(&self) -> impl Iterator<Item = &str> {
+		self.entries.iter().filter_map(|entry| match entry {
+			Entry::Var { key, .. } => Some(key.as_str()),
+			Entry::Other(_) => None,
+		})
+	}
+
+	/// Add or overwrite a variable. Existing definitions are updated in place.
+	pub fn set(&mut self, key: &str, value: &str) {
+		let raw = format!("{key}={}", quote(value));
+
+		let mut found = false;
+		self.entries.retain_mut(|entry| match entry {
+			Entry::Var { key: k, raw: r } if k == key => {
+				if found {
+					return false;
+				}
+
+				found = true;
+				r.clone_from(&raw);
+				true
+			},
+			_ => true,
+		});
+
+		if !found {
+			self.entries.push(Entry::Var {
+				raw,
+				key: key.to_string(),
+			});
+		}
+	}
+
+	/// Remove a variable, returning whether it was defined.
+	pub fn unset(&mut self, key: &str) -> bool {
+		let len = self.entries.len();
+
+		self.entries
+			.retain(|entry| !matches!(entry, Entry::Var { key: k, .. } if k == key));
+
+		self.entries.len() != len
+	}
+
+	/// Remove every variable, keeping comments around.
+	pub fn clear(&mut self) {
+		self.entries
+			.retain(|entry| matches!(entry, Entry::Other(_)));
+	}
+
+	/// Atomically write the file back to disk, keeping its permissions.
+	pub fn save(&self) -> io::Result<()> {
+		let mut contents = self
+			.entries
+			.iter()
+			.map(|entry| match entry {
+				Entry::Var { raw, .. } | Entry::Other(raw) => raw.as_str(),
+			})
+			.collect::<Vec<_>>()
+			.join("\n");
+		contents.push('\n');
+
+		let mut tmp_name = self.path.file_name().unwrap_or_default().to_os_string();
+		tmp_name.push(format!(".{}.orbit-tmp", Uuid::now_v7()));
+		let tmp_path = self.path.with_file_name(tmp_name);
+
+		let write = || {
+			fs::write(&tmp_path, contents)?;
+
+			if let Ok(metadata) = fs::metadata(&self.path) {
+				fs::set_permissions(&tmp_path, metadata.permissions())?;
+			}
+
+			fs::rename(&tmp_path, &self.path)
+		};
+
+		let result = write();
+		if result.is_err() {
+			_ = fs::remove_file(&tmp_path);
+		}
+
+		result
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+/// Whether the given string is a valid variable name.
+pub fn is_valid_key(key: &str) -> bool {
+	let mut chars = key.chars();
+
+	chars
+		.next()
+		.is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse(contents: &str) -> Vec<Entry> {
+	let mut entries = vec![];
+	let mut lines = contents.lines();
+
+	while let Some(line) = lines.next() {
+		let trimmed = line.trim_start();
+		let definition = trimmed.strip_prefix("export ").unwrap_or(trimmed);
+
+		let Some((key, value)) = definition
+			.split_once('=')
+			.filter(|(key, _)| !trimmed.starts_with('#') && is_valid_key(key.trim()))
+		else {
+			entries.push(Entry::Other(line.to_string()));
+			continue;
+		};
+
+		let mut raw = line.to_string();
+		let value = value.trim_start();
+
+		// quoted values may span several lines, so keep consuming until the quote is closed
+		if let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') {
+			let mut rest = value[1..].to_string();
+
+			while !is_closed(&rest, quote) {
+				let Some(next) = lines.next() else { break };
+
+				raw.push('\n');
+				raw.push_str(next);
+				rest.push('\n');
+				rest.push_str(next);
+			}
+		}
+
+		entries.push(Entry::Var {
+			raw,
+			key: key.trim().to_string(),
+		});
+	}
+
+	entries
+}
+
+fn is_closed(value: &str, quote: char) -> bool {
+	let mut escaped = false;
+
+	for c in value.chars() {
+		match c {
+			'\\' if quote == '"' && !escaped => escaped = true,
+			c if c == quote && !escaped => return true,
+			_ => escaped = false,
+		}
+	}
+
+	false
+}
+
+fn quote(value: &str) -> String {
+	if value
+		.chars()
+		.all(|c| c.is_ascii_alphanumeric() || "_-./:@+,".contains(c))
+	{
+		return value.to_string();
+	}
+
+	if !value.contains(['\'', '\n']) {
+		return format!("'{value}'");
+	}
+
+	let escaped = value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('$', "\\$")
+		.replace('\n', "\\n");
+
+	format!("\"{escaped}\"")
+}
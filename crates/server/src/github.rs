@@ -0,0 +1,417 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use http::{header, StatusCode};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	fmt, fs,
+	path::Path,
+	sync::{Arc, Mutex},
+};
+
+use crate::{config::GitHubApp, secrets::Secret};
+
+/// How long before they expire installation tokens are replaced, so they don't expire mid-deployment.
+const REFRESH_MARGIN: Duration = Duration::minutes(5);
+
+/// A client for the GitHub API.
+pub fn client() -> reqwest::Client {
+	reqwest::Client::builder()
+		.user_agent("orbit-deployer")
+		.build()
+		.unwrap()
+}
+
+/// How a site's repository is accessed on GitHub.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+	/// The base URL of the GitHub API, without a trailing slash.
+	pub api_url: String,
+	/// Where the credentials come from, for error messages.
+	pub source: String,
+	auth: Auth,
+}
+
+#[derive(Debug, Clone)]
+enum Auth {
+	Token(Secret),
+	App(Arc<App>),
+}
+
+impl Credentials {
+	pub fn token(api_url: &str, source: String, token: Secret) -> Self {
+		Self {
+			source,
+			auth: Auth::Token(token),
+			api_url: api_url.trim_end_matches('/').to_string(),
+		}
+	}
+
+	pub fn app(api_url: &str, app: Arc<App>) -> Self {
+		Self {
+			source: format!("GitHub App {}", app.id),
+			auth: Auth::App(app),
+			api_url: api_url.trim_end_matches('/').to_string(),
+		}
+	}
+
+	/// The token, if it's a static one rather than fetched when needed.
+	pub fn static_token(&self) -> Option<&str> {
+		match &self.auth {
+			Auth::Token(token) => Some(token.expose()),
+			Auth::App(_) => None,
+		}
+	}
+
+	/// A token with access to the repository (as `owner/repo`). Apps get an installation
+	/// token scoped to the repository, which is reused until it's close to expiring.
+	pub async fn access_token(
+		&self,
+		client: &reqwest::Client,
+		cache: &TokenCache,
+		repository: &str,
+	) -> Result<Secret> {
+		let app = match &self.auth {
+			Auth::Token(token) => return Ok(token.clone()),
+			Auth::App(app) => app,
+		};
+
+		let key = (self.api_url.clone(), app.id, repository.to_lowercase());
+		if let Some(token) = cache.get(&key) {
+			return Ok(token);
+		}
+
+		let token = app
+			.installation_token(client, &self.api_url, repository)
+			.await?;
+		let secret = token.token.clone();
+		cache.insert(key, token);
+
+		Ok(secret)
+	}
+}
+
+/// A GitHub App, which authenticates with short-lived installation tokens.
+pub struct App {
+	id: u64,
+	installation_id: Option<u64>,
+	key: EncodingKey,
+}
+
+impl fmt::Debug for App {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("App")
+			.field("id", &self.id)
+			.field("installation_id", &self.installation_id)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Serialize)]
+struct AppClaims {
+	iat: i64,
+	exp: i64,
+	iss: u64,
+}
+
+#[derive(Deserialize)]
+struct Installation {
+	id: u64,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+	#[serde(deserialize_with = "secret")]
+	token: Secret,
+	expires_at: DateTime<Utc>,
+}
+
+impl App {
+	/// Load the app's private key, resolving its path from the given directory.
+	pub fn load(config: &GitHubApp, dir: &Path) -> Result<Self> {
+		let path = dir.join(&config.private_key_file);
+		let pem = fs::read(&path)
+			.with_context(|| format!("Could not read GitHub App private key {}", path.display()))?;
+
+		Ok(Self {
+			id: config.id,
+			installation_id: config.installation_id,
+			key: EncodingKey::from_rsa_pem(&pem)
+				.with_context(|| format!("Invalid GitHub App private key {}", path.display()))?,
+		})
+	}
+
+	/// A JWT identifying the app. GitHub rejects ones valid for more than ten minutes,
+	/// and the issue time is backdated to allow for clock drift.
+	fn jwt(&self) -> Result<String> {
+		let now = Utc::now().timestamp();
+		let claims = AppClaims {
+			iss: self.id,
+			iat: now - 60,
+			exp: now + 9 * 60,
+		};
+
+		Ok(jsonwebtoken::encode(
+			&Header::new(Algorithm::RS256),
+			&claims,
+			&self.key,
+		)?)
+	}
+
+	async fn installation_token(
+		&self,
+		client: &reqwest::Client,
+		api_url: &str,
+		repository: &str,
+	) -> Result<InstallationToken> {
+		let jwt = self.jwt()?;
+
+		let installation_id = if let Some(id) = self.installation_id {
+			id
+		} else {
+			let response = client
+				.get(format!("{api_url}/repos/{repository}/installation"))
+				.bearer_auth(&jwt)
+				.header(header::ACCEPT, "application/vnd.github+json")
+				.send()
+				.await?;
+
+			match response.status() {
+				StatusCode::NOT_FOUND => {
+					bail!("GitHub App {} is not installed on {repository}", self.id)
+				},
+				status if !status.is_success() => bail!(
+					"Could not find the installation of GitHub App {} on {repository} ({status})",
+					self.id
+				),
+				_ => response.json::<Installation>().await?.id,
+			}
+		};
+
+		// scope the token to the repository, so it can't be used to reach anything else
+		let name = repository
+			.split_once('/')
+			.map_or(repository, |(_, name)| name);
+
+		let response = client
+			.post(format!(
+				"{api_url}/app/installations/{installation_id}/access_tokens"
+			))
+			.bearer_auth(&jwt)
+			.header(header::ACCEPT, "application/vnd.github+json")
+			.json(&serde_json::json!({ "repositories": [name] }))
+			.send()
+			.await?;
+
+		if !response.status().is_success() {
+			bail!(
+				"Could not create a token for {repository} from installation {installation_id} ({})",
+				response.status()
+			);
+		}
+
+		Ok(response.json().await?)
+	}
+}
+
+/// Installation tokens, keyed by API URL, app and repository, kept until shortly before they expire.
+#[derive(Default)]
+pub struct TokenCache(Mutex<HashMap<(String, u64, String), InstallationToken>>);
+
+impl TokenCache {
+	fn get(&self, key: &(String, u64, String)) -> Option<Secret> {
+		let mut tokens = self.0.lock().unwrap();
+		tokens.retain(|_, token| token.expires_at - Utc::now() > REFRESH_MARGIN);
+
+		tokens.get(key).map(|token| token.token.clone())
+	}
+
+	fn insert(&self, key: (String, u64, String), token: InstallationToken) {
+		self.0.lock().unwrap().insert(key, token);
+	}
+}
+
+fn secret<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+	String::deserialize(deserializer).map(Secret::from)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::{
+		extract::{Path as UrlPath, State},
+		http::HeaderMap,
+		routing::{get, post},
+		Json, Router,
+	};
+	use jsonwebtoken::{DecodingKey, Validation};
+	use serde_json::{json, Value};
+	use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+	const PUBLIC_KEY: &[u8] = include_bytes!("../tests/fixtures/github-app.pub.pem");
+
+	/// A stand-in for the GitHub API, which installs the app with id 1 everywhere but on
+	/// `owner/missing` and `owner/broken`, and issues tokens valid for `lifetime` minutes.
+	struct Mock {
+		issued: AtomicUsize,
+		lifetime: AtomicI64,
+	}
+
+	/// Check the request is authenticated with a JWT for app 1, valid for at most ten minutes.
+	fn authenticate(headers: &HeaderMap) -> Result<(), StatusCode> {
+		let jwt = headers
+			.get(header::AUTHORIZATION)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "))
+			.ok_or(StatusCode::UNAUTHORIZED)?;
+
+		let validation = Validation::new(Algorithm::RS256);
+		let claims = jsonwebtoken::decode::<Value>(
+			jwt,
+			&DecodingKey::from_rsa_pem(PUBLIC_KEY).unwrap(),
+			&validation,
+		)
+		.map_err(|_| StatusCode::UNAUTHORIZED)?
+		.claims;
+
+		let lifetime = claims["exp"]
+			.as_i64()
+			.zip(claims["iat"].as_i64())
+			.map(|(exp, iat)| exp - iat);
+		if claims["iss"] != 1 || lifetime.is_none_or(|lifetime| lifetime > 10 * 60) {
+			return Err(StatusCode::UNAUTHORIZED);
+		}
+
+		Ok(())
+	}
+
+	async fn installation(
+		headers: HeaderMap,
+		UrlPath((_, repo)): UrlPath<(String, String)>,
+	) -> Result<Json<Value>, StatusCode> {
+		authenticate(&headers)?;
+
+		match repo.as_str() {
+			"missing" => Err(StatusCode::NOT_FOUND),
+			"broken" => Err(StatusCode::INTERNAL_SERVER_ERROR),
+			_ => Ok(Json(json!({ "id": 42 }))),
+		}
+	}
+
+	async fn access_token(
+		State(mock): State<Arc<Mock>>,
+		headers: HeaderMap,
+		UrlPath(id): UrlPath<u64>,
+		Json(body): Json<Value>,
+	) -> Result<Json<Value>, StatusCode> {
+		authenticate(&headers)?;
+		if id != 42 || body["repositories"].as_array().map(Vec::len) != Some(1) {
+			return Err(StatusCode::UNPROCESSABLE_ENTITY);
+		}
+
+		let issued = mock.issued.fetch_add(1, Ordering::SeqCst) + 1;
+		let lifetime = Duration::minutes(mock.lifetime.load(Ordering::SeqCst));
+
+		Ok(Json(json!({
+			"token": format!("ghs_{issued}"),
+			"expires_at": Utc::now() + lifetime,
+		})))
+	}
+
+	async fn serve() -> (Arc<Mock>, Credentials) {
+		let mock = Arc::new(Mock {
+			issued: AtomicUsize::new(0),
+			lifetime: AtomicI64::new(60),
+		});
+		let router = Router::new()
+			.route("/repos/:owner/:repo/installation", get(installation))
+			.route("/app/installations/:id/access_tokens", post(access_token))
+			.with_state(mock.clone());
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/", listener.local_addr().unwrap());
+		tokio::spawn(async move { axum::serve(listener, router).await });
+
+		let app = App::load(
+			&GitHubApp {
+				id: 1,
+				private_key_file: "github-app.pem".into(),
+				installation_id: None,
+			},
+			&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures"),
+		)
+		.unwrap();
+
+		(mock, Credentials::app(&url, Arc::new(app)))
+	}
+
+	#[tokio::test]
+	async fn app_tokens_are_reused_until_close_to_expiring() {
+		let (mock, credentials) = serve().await;
+		let (client, cache) = (client(), TokenCache::default());
+		let token = |repository| credentials.access_token(&client, &cache, repository);
+
+		assert_eq!(token("owner/repo").await.unwrap().expose(), "ghs_1");
+		assert_eq!(token("owner/repo").await.unwrap().expose(), "ghs_1");
+		assert_eq!(token("Owner/Repo").await.unwrap().expose(), "ghs_1");
+		assert_eq!(token("owner/other").await.unwrap().expose(), "ghs_2");
+
+		// tokens expiring within the margin are replaced on every use
+		mock.lifetime
+			.store(REFRESH_MARGIN.num_minutes() - 1, Ordering::SeqCst);
+		assert_eq!(token("owner/short").await.unwrap().expose(), "ghs_3");
+		assert_eq!(token("owner/short").await.unwrap().expose(), "ghs_4");
+		assert_eq!(token("owner/repo").await.unwrap().expose(), "ghs_1");
+		assert_eq!(mock.issued.load(Ordering::SeqCst), 4);
+	}
+
+	#[tokio::test]
+	async fn app_lookup_errors_are_reported() {
+		let (mock, credentials) = serve().await;
+		let (client, cache) = (client(), TokenCache::default());
+
+		let error = credentials
+			.access_token(&client, &cache, "owner/missing")
+			.await
+			.unwrap_err();
+		assert_eq!(
+			error.to_string(),
+			"GitHub App 1 is not installed on owner/missing"
+		);
+
+		let error = credentials
+			.access_token(&client, &cache, "owner/broken")
+			.await
+			.unwrap_err();
+		assert!(
+			error.to_string().contains("500 Internal Server Error"),
+			"{error}"
+		);
+
+		assert_eq!(mock.issued.load(Ordering::SeqCst), 0);
+	}
+
+	#[tokio::test]
+	async fn token_creation_errors_are_reported() {
+		let (_, credentials) = serve().await;
+		let Auth::App(app) = &credentials.auth else {
+			unreachable!()
+		};
+		let app = App {
+			id: app.id,
+			installation_id: Some(7),
+			key: app.key.clone(),
+		};
+
+		let error = app
+			.installation_token(&client(), &credentials.api_url, "owner/repo")
+			.await
+			.err()
+			.unwrap();
+		assert_eq!(
+			error.to_string(),
+			"Could not create a token for owner/repo from installation 7 (422 Unprocessable Entity)"
+		);
+	}
+}
@@ -0,0 +1,229 @@
+use std::{env, ops::Range};
+use toml_edit::{visit::Visit, visit_mut::VisitMut, DocumentMut, Formatted, ImDocument};
+
+/// An environment variable that's referenced without a default, but isn't set.
+pub struct Unset {
+	pub name: String,
+	/// Where the value referencing it is in the parsed text.
+	pub span: Option<Range<usize>>,
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` references to environment variables in every string
+/// value of a parsed TOML document, failing on the first unset variable without a default.
+/// As in shells, the default is also used for variables that are set but empty.
+/// `$${` is left as a literal `${`, and references that aren't to a variable (like
+/// `${secret:NAME}`) are left untouched. Comments and formatting are preserved.
+pub fn expand(document: ImDocument<&str>) -> Result<DocumentMut, Unset> {
+	// spans are dropped once the document is editable, so note where each value was first
+	let spans = string_spans(&document);
+
+	let mut document = document.into_mut();
+	let mut expander = Expander {
+		spans: spans.into_iter(),
+		error: None,
+	};
+	expander.visit_document_mut(&mut document);
+
+	expander.error.map_or(Ok(document), Err)
+}
+
+/// Map an offset in `expanded`, the text of a document after [`expand`], back to the same place
+/// in the `original` text. Only string values differ between the two, so an offset inside an
+/// expanded value points at the start of the original one. Returns `None` if anything else
+/// changed, like when the document was upgraded to a newer version of the config format.
+pub fn original_offset(original: &str, expanded: &str, offset: usize) -> Option<usize> {
+	let spans = |text| {
+		ImDocument::parse(text).ok().and_then(|document| {
+			string_spans(&document)
+				.into_iter()
+				.collect::<Option<Vec<_>>>()
+		})
+	};
+	let (original_spans, expanded_spans) = (spans(original)?, spans(expanded)?);
+	if original_spans.len() != expanded_spans.len() {
+		return None;
+	}
+
+	let mut values = original_spans
+		.into_iter()
+		.zip(expanded_spans)
+		.collect::<Vec<_>>();
+	values.sort_by_key(|(_, expanded)| expanded.start);
+
+	let (mut original_at, mut expanded_at, mut mapped) = (0, 0, None);
+	for (original_span, expanded_span) in values {
+		// everything between the values has to be unchanged
+		if original.get(original_at..original_span.start)?
+			!= expanded.get(expanded_at..expanded_span.start)?
+		{
+			return None;
+		}
+
+		if mapped.is_none() && offset < expanded_span.end {
+			mapped = Some(if offset < expanded_span.start {
+				original_at + (offset - expanded_at)
+			} else if original[original_span.clone()] == expanded[expanded_span.clone()] {
+				original_span.start + (offset - expanded_span.start)
+			} else {
+				original_span.start
+			});
+		}
+
+		(original_at, expanded_at) = (original_span.end, expanded_span.end);
+	}
+
+	if original[original_at..] != expanded[expanded_at..] {
+		return None;
+	}
+
+	mapped.or_else(|| {
+		offset
+			.checked_sub(expanded_at)
+			.map(|offset| original_at + offset)
+	})
+}
+
+/// The spans of every string value in a parsed document, in the order they're visited.
+fn string_spans(document: &ImDocument<&str>) -> Vec<Option<Range<usize>>> {
+	let mut spans = Spans(Vec::new());
+	spans.visit_table(document.as_table());
+
+	spans.0
+}
+
+struct Spans(Vec<Option<Range<usize>>>);
+
+impl Visit<'_> for Spans {
+	fn visit_string(&mut self, node: &Formatted<String>) {
+		self.0.push(node.span());
+	}
+}
+
+struct Expander {
+	/// The spans of the string values left to visit, from [`string_spans`].
+	spans: std::vec::IntoIter<Option<Range<usize>>>,
+	/// The first variable that couldn't be expanded.
+	error: Option<Unset>,
+}
+
+impl VisitMut for Expander {
+	fn visit_string_mut(&mut self, node: &mut Formatted<String>) {
+		let span = self.spans.next().flatten();
+		if !node.value().contains('$') || self.error.is_some() {
+			return;
+		}
+
+		match expand_str(node.value()) {
+			Ok(expanded) => {
+				let mut value = Formatted::new(expanded);
+				*value.decor_mut() = node.decor().clone();
+				*node = value;
+			},
+			Err(name) => self.error = Some(Unset { name, span }),
+		}
+	}
+}
+
+/// Expand the references in a string, returning the name of the first unset variable without a default.
+fn expand_str(text: &str) -> Result<String, String> {
+	let mut expanded = String::with_capacity(text.len());
+	let mut rest = text;
+
+	while let Some(start) = rest.find('$') {
+		expanded.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		if let Some(escaped) = rest.strip_prefix("$${") {
+			expanded.push_str("${");
+			rest = escaped;
+			continue;
+		}
+
+		let Some((name, default, after)) = reference(rest) else {
+			expanded.push('$');
+			rest = &rest[1..];
+			continue;
+		};
+
+		// like in shells, `:-` also replaces variables that are set but empty
+		match (env::var(name), default) {
+			(Ok(value), Some(default)) if value.is_empty() => expanded.push_str(default),
+			(Ok(value), _) => expanded.push_str(&value),
+			(Err(_), Some(default)) => expanded.push_str(default),
+			(Err(_), None) => return Err(name.to_string()),
+		}
+		rest = after;
+	}
+
+	expanded.push_str(rest);
+	Ok(expanded)
+}
+
+/// Parse a `${NAME}` or `${NAME:-default}` reference at the start of the text, returning
+/// the variable's name, its default and the text after the reference.
+fn reference(text: &str) -> Option<(&str, Option<&str>, &str)> {
+	let inner = text.strip_prefix("${")?;
+	let end = inner.find('}')?;
+	let (reference, after) = (&inner[..end], &inner[end + 1..]);
+
+	let (name, default) = match reference.split_once(":-") {
+		Some((name, default)) => (name, Some(default)),
+		None => (reference, None),
+	};
+
+	let is_variable = name
+		.chars()
+		.next()
+		.is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+	is_variable.then_some((name, default, after))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const ORIGINAL: &str = "path = \"${ORBIT_TEST_BASE:-/srv}/site\" # where it lives\nrepo = 5\n";
+
+	fn expanded() -> String {
+		expand(ImDocument::parse(ORIGINAL).unwrap())
+			.ok()
+			.unwrap()
+			.to_string()
+	}
+
+	#[test]
+	fn maps_offsets_after_expanded_values() {
+		let expanded = expanded();
+		assert_eq!(
+			expanded,
+			"path = \"/srv/site\" # where it lives\nrepo = 5\n"
+		);
+
+		let offset = expanded.find('5').unwrap();
+		assert_eq!(
+			original_offset(ORIGINAL, &expanded, offset),
+			ORIGINAL.find('5')
+		);
+		assert_eq!(original_offset(ORIGINAL, &expanded, 0), Some(0));
+	}
+
+	#[test]
+	fn maps_offsets_inside_expanded_values_to_their_start() {
+		let expanded = expanded();
+		let offset = expanded.find("site").unwrap();
+
+		assert_eq!(
+			original_offset(ORIGINAL, &expanded, offset),
+			ORIGINAL.find('"')
+		);
+	}
+
+	#[test]
+	fn gives_up_on_other_changes() {
+		let expanded = expanded().replace("# where", "# how");
+
+		assert_eq!(original_offset(ORIGINAL, &expanded, 0), None);
+	}
+}
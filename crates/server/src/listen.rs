@@ -0,0 +1,186 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+use std::{env, fmt, io, net::SocketAddr};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpListener,
+};
+
+use crate::config::Listen;
+
+#[cfg(unix)]
+use std::{
+	fs,
+	os::{
+		fd::{FromRawFd, OwnedFd},
+		unix::fs::{FileTypeExt, PermissionsExt},
+	},
+	path::PathBuf,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// The file descriptor systemd passes the first socket as.
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// How many pending connections each socket queues.
+const BACKLOG: i32 = 1024;
+
+/// A connection accepted by a [`Listener`].
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A socket the server accepts connections on.
+pub enum Listener {
+	Tcp(TcpListener),
+	/// A Unix domain socket, along with its path if we created it (and should clean it up).
+	#[cfg(unix)]
+	Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+	/// Accept a connection, returning the client's address if it connected over TCP.
+	pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, Option<SocketAddr>)> {
+		match self {
+			Self::Tcp(listener) => {
+				let (stream, addr) = listener.accept().await?;
+				stream.set_nodelay(true)?;
+
+				Ok((Box::new(stream), Some(addr)))
+			},
+			#[cfg(unix)]
+			Self::Unix(listener, _) => {
+				let (stream, _) = listener.accept().await?;
+
+				Ok((Box::new(stream), None))
+			},
+		}
+	}
+}
+
+impl fmt::Display for Listener {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Tcp(listener) => match listener.local_addr() {
+				Ok(addr) => write!(f, "{addr}"),
+				Err(_) => write!(f, "tcp"),
+			},
+			#[cfg(unix)]
+			Self::Unix(listener, _) => match listener
+				.local_addr()
+				.ok()
+				.and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+			{
+				Some(path) => write!(f, "unix:{path}"),
+				None => write!(f, "unix"),
+			},
+		}
+	}
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+	fn drop(&mut self) {
+		if let Self::Unix(_, Some(path)) = self {
+			_ = fs::remove_file(path);
+		}
+	}
+}
+
+/// Take over the sockets systemd passed us if started through socket activation,
+/// or bind the configured addresses otherwise. With no addresses configured,
+/// listens on `$PORT` (8000 by default) on every IPv4 interface.
+pub fn bind(config: &[Listen]) -> Result<Vec<Listener>> {
+	#[cfg(unix)]
+	if let Some(listeners) = systemd_sockets()? {
+		return Ok(listeners);
+	}
+
+	if config.is_empty() {
+		let port = env::var("PORT").map_or(Ok(8000), |p| p.parse())?;
+
+		return Ok(vec![bind_tcp(SocketAddr::from(([0, 0, 0, 0], port)))?]);
+	}
+
+	config
+		.iter()
+		.map(|listen| match listen {
+			Listen::Tcp(addr) => bind_tcp(*addr),
+			Listen::Unix { path, mode } => {
+				#[cfg(unix)]
+				return bind_unix(path.clone(), *mode)
+					.with_context(|| format!("Could not listen on {}", path.display()));
+
+				#[cfg(not(unix))]
+				anyhow::bail!(
+					"Unix sockets are not supported on this platform ({})",
+					path.display()
+				);
+			},
+		})
+		.collect()
+}
+
+fn bind_tcp(addr: SocketAddr) -> Result<Listener> {
+	let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+
+	// keep IPv4 and IPv6 addresses independent, so both `0.0.0.0` and `[::]` can be listed
+	if addr.is_ipv6() {
+		socket.set_only_v6(true)?;
+	}
+	socket.set_reuse_address(true)?;
+	socket.set_nonblocking(true)?;
+	socket
+		.bind(&addr.into())
+		.with_context(|| format!("Could not listen on {addr}"))?;
+	socket.listen(BACKLOG)?;
+
+	Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: PathBuf, mode: Option<u32>) -> Result<Listener> {
+	// a socket left behind by a previous run would make binding fail
+	if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+		fs::remove_file(&path)?;
+	}
+
+	let listener = UnixListener::bind(&path)?;
+	if let Some(mode) = mode {
+		fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+	}
+
+	Ok(Listener::Unix(listener, Some(path)))
+}
+
+/// The sockets passed through systemd socket activation, if any.
+/// See <https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html>.
+#[cfg(unix)]
+fn systemd_sockets() -> Result<Option<Vec<Listener>>> {
+	let (Ok(pid), Ok(fds)) = (env::var("LISTEN_PID"), env::var("LISTEN_FDS")) else {
+		return Ok(None);
+	};
+
+	if pid.parse::<u32>().ok() != Some(std::process::id()) {
+		return Ok(None);
+	}
+
+	let fds = fds.parse::<i32>().context("Invalid $LISTEN_FDS")?;
+
+	(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+		.map(|fd| {
+			// SAFETY: systemd hands these file descriptors over to us, and nothing else owns them.
+			let socket = Socket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+			socket.set_cloexec(true)?;
+			socket.set_nonblocking(true)?;
+
+			if socket.local_addr()?.is_unix() {
+				Ok(Listener::Unix(UnixListener::from_std(socket.into())?, None))
+			} else {
+				Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+			}
+		})
+		.collect::<Result<_>>()
+		.map(Some)
+}
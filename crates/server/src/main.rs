@@ -1,32 +1,95 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
-use std::env;
+use std::path::PathBuf;
 
 use anyhow::Result;
+use clap::{Parser, Subcommand};
 use config::Config;
 use dotenvy::dotenv;
 use tracing_subscriber::{
 	prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
+mod access;
+mod audit;
+mod auth;
 mod config;
 mod deploy;
+mod dotenv;
+mod github;
+mod interpolate;
+mod listen;
+mod migrate;
 mod misc;
+mod oidc;
+mod redact;
+mod reload;
 mod routes;
+mod secrets;
 mod server;
+mod signing;
+mod tls;
+
+#[derive(Debug, Parser)]
+#[clap(
+	name = "orbit-server",
+	about = "🛰️ Simple & secure zero-downtime deployments for your Laravel apps.",
+	version,
+	author
+)]
+struct Cli {
+	/// Path to the Orbit config file.
+	#[arg(short, long, env = "ORBIT_CONFIG")]
+	config: PathBuf,
+
+	#[clap(subcommand)]
+	command: Option<Commands>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Commands {
+	/// Start the Orbit server. This is the default when no command is given.
+	Serve,
+
+	/// Check or upgrade the Orbit config.
+	Config {
+		#[clap(subcommand)]
+		command: config::Command,
+	},
+
+	/// Manage the encrypted secrets in the Orbit config.
+	Secrets {
+		#[clap(subcommand)]
+		command: secrets::Command,
+	},
+
+	/// Generate API tokens for the Orbit config.
+	Tokens {
+		#[clap(subcommand)]
+		command: auth::Command,
+	},
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
 	dotenv().ok();
 
+	let cli = Cli::parse();
+
 	tracing_subscriber::registry()
 		.with(tracing_subscriber::fmt::layer().with_filter(
 			EnvFilter::try_from_default_env().unwrap_or_else(|_| "orbit_server=info".into()),
 		))
 		.init();
 
-	let config =
-		Config::load(env::var("ORBIT_CONFIG").expect("$ORBIT_CONFIG not found"))?.validate()?;
+	match cli.command.unwrap_or(Commands::Serve) {
+		Commands::Serve => {
+			let config = Config::load(&cli.config)?.validate()?;
 
-	server::start(config).await
+			server::start(cli.config, config).await
+		},
+		Commands::Config { command } => command.run(&cli.config).await,
+		Commands::Secrets { command } => command.run(&cli.config),
+		Commands::Tokens { command } => command.run(),
+	}
 }
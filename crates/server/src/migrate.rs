@@ -0,0 +1,77 @@
+use anyhow::{bail, Context, Result};
+use slug::slugify;
+use toml_edit::{DocumentMut, Item, Table, Value};
+
+/// The latest version of the config format.
+pub const LATEST: i64 = 2;
+
+/// Upgrade a config document to the latest version, returning the version it was written for.
+/// Comments and formatting are preserved, so the result can be written back to the file.
+pub fn upgrade(doc: &mut DocumentMut) -> Result<i64> {
+	let Some(version) = doc.get_mut("version").and_then(Item::as_value_mut) else {
+		bail!("Missing `version`");
+	};
+
+	let Some(written_for) = version.as_integer().filter(|v| (1..=LATEST).contains(v)) else {
+		bail!("Unsupported version: {version}");
+	};
+
+	let decor = version.decor().clone();
+	*version = Value::from(LATEST);
+	*version.decor_mut() = decor;
+
+	upgrade_sites(doc, written_for)?;
+
+	Ok(written_for)
+}
+
+/// Upgrade the sites in a config document, or in a file it includes, from the given version.
+pub fn upgrade_sites(doc: &mut DocumentMut, version: i64) -> Result<()> {
+	if version < 2 {
+		key_sites_by_slug(doc)?;
+	}
+
+	Ok(())
+}
+
+/// Version 2 only accepts sites keyed by their slug (`[sites.my-site]`), so renaming a site
+/// can't change how it's referred to. Sites listed as `[[sites]]` are keyed by their
+/// slugified name, which is what they were referred to by before.
+fn key_sites_by_slug(doc: &mut Table) -> Result<()> {
+	let Some(sites) = doc.remove("sites") else {
+		return Ok(());
+	};
+	let sites = match sites {
+		Item::ArrayOfTables(sites) => sites,
+		Item::Table(_) => {
+			doc.insert("sites", sites);
+			return Ok(());
+		},
+		_ => bail!("`sites` must be a table of sites, like `[sites.my-site]`"),
+	};
+
+	let mut keyed = Table::new();
+	keyed.set_implicit(true);
+
+	for site in sites {
+		let name = site
+			.get("name")
+			.and_then(Item::as_str)
+			.context("Every site needs a `name`")?;
+
+		if name.contains("${") {
+			bail!("Can't tell the slug of site {name}, since its name references environment variables. Key it by its slug first, like `[sites.my-site]`.");
+		}
+
+		let slug = slugify(name);
+		if keyed.contains_key(&slug) {
+			bail!("Several sites use the slug {slug}. Key them by their slug first, like `[sites.my-site]`.");
+		}
+
+		keyed.insert(&slug, Item::Table(site));
+	}
+
+	doc.insert("sites", Item::Table(keyed));
+
+	Ok(())
+}
@@ -7,20 +7,24 @@ use axum::response::{
 	sse::{Event, KeepAlive},
 	IntoResponse,
 };
+use bytes::Bytes;
 use futures_util::Stream;
 use indexmap::IndexMap;
 use orbit_types::Log;
 use schemars::JsonSchema;
 use std::{
+	ffi::{OsStr, OsString},
 	fs,
 	io::{self, Read},
-	path::Path,
+	path::{Component, Path, PathBuf},
 	process::Stdio,
 	rc::Rc,
 };
+use tar::EntryType;
 use tokio::{
 	io::{AsyncBufReadExt, BufReader},
 	process::Command,
+	sync::mpsc,
 };
 
 #[derive(Debug)]
@@ -93,28 +97,262 @@ where
 	}
 }
 
-pub fn untar_to<R: Read>(mut tar: tar::Archive<R>, path: &Path) -> io::Result<()> {
+/// Reads the chunks sent through a channel, so blocking code (like extracting an archive)
+/// can consume a download while it's still arriving. Must be read from a blocking task.
+pub struct ChannelReader {
+	chunks: mpsc::Receiver<io::Result<Bytes>>,
+	current: Bytes,
+}
+
+impl ChannelReader {
+	pub const fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+		Self {
+			chunks,
+			current: Bytes::new(),
+		}
+	}
+}
+
+impl Read for ChannelReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.current.is_empty() {
+			match self.chunks.blocking_recv() {
+				Some(chunk) => self.current = chunk?,
+				None => return Ok(0),
+			}
+		}
+
+		let len = buf.len().min(self.current.len());
+		buf[..len].copy_from_slice(&self.current.split_to(len));
+
+		Ok(len)
+	}
+}
+
+/// Extract a tarball into `path`, without the first `strip_components` directories of each entry
+/// (like the top-level directory GitHub wraps repositories in). Files keep their permissions
+/// (minus setuid and setgid bits) and symlinks are recreated, but entries that could reach outside
+/// of `path` (absolute paths, `..` components, symlinks pointing out of the tree or anything
+/// written through them) are rejected, as are hard links and devices.
+pub fn untar_to<R: Read>(
+	mut tar: tar::Archive<R>,
+	path: &Path,
+	strip_components: usize,
+) -> io::Result<()> {
+	fs::create_dir_all(path)?;
+	let root = path.canonicalize()?;
+
 	for entry in tar.entries()? {
-		let mut file = entry?;
-		let file_path = file.path()?.into_owned();
-		let file_path = file_path
-			.strip_prefix(file_path.components().next().unwrap())
-			.unwrap()
-			.to_owned();
+		let mut entry = entry?;
+		let entry_type = entry.header().entry_type();
+		let entry_path = entry.path()?.into_owned();
+
+		// GitHub records the commit in a global header, which isn't a file
+		if entry_type.is_pax_global_extensions() {
+			continue;
+		}
+
+		let parts = relative_parts(&entry_path, strip_components)?;
+		let Some((name, dirs)) = parts.split_last() else {
+			// one of the stripped directories itself
+			continue;
+		};
+
+		let parent = create_dirs_inside(&root, dirs, &entry_path)?;
+
+		match entry_type {
+			EntryType::Regular | EntryType::Continuous | EntryType::Directory => {},
+			EntryType::Symlink => {
+				let target = entry
+					.link_name()?
+					.ok_or_else(|| rejected(&entry_path, "it's a symlink without a target"))?;
+
+				check_link_target(&root, &parent, &target)
+					.map_err(|reason| rejected(&entry_path, reason))?;
+			},
+			_ => {
+				return Err(rejected(
+					&entry_path,
+					"only files, directories and symlinks are supported",
+				))
+			},
+		}
+
+		entry.unpack(parent.join(name))?;
+	}
+
+	Ok(())
+}
+
+/// Extract a zip archive into `path`, with the same checks as [`untar_to`]. Files keep their
+/// Unix permissions (minus setuid and setgid bits) when the archive records them.
+pub fn unzip_to<R: Read + io::Seek>(reader: R, path: &Path) -> io::Result<()> {
+	let mut zip = zip::ZipArchive::new(reader).map_err(io::Error::other)?;
+
+	fs::create_dir_all(path)?;
+	let root = path.canonicalize()?;
+
+	for i in 0..zip.len() {
+		let mut entry = zip.by_index(i).map_err(io::Error::other)?;
+		let entry_path = PathBuf::from(entry.name());
+
+		let parts = relative_parts(&entry_path, 0)?;
+		if entry.is_dir() {
+			create_dirs_inside(&root, &parts, &entry_path)?;
+			continue;
+		}
+
+		let Some((name, dirs)) = parts.split_last() else {
+			continue;
+		};
+		let parent = create_dirs_inside(&root, dirs, &entry_path)?;
+
+		if entry.is_symlink() {
+			let mut target = String::new();
+			entry.read_to_string(&mut target)?;
+
+			check_link_target(&root, &parent, Path::new(&target))
+				.map_err(|reason| rejected(&entry_path, reason))?;
+
+			#[cfg(unix)]
+			std::os::unix::fs::symlink(&target, parent.join(name))?;
+			#[cfg(not(unix))]
+			return Err(rejected(&entry_path, "symlinks are only supported on Unix"));
 
-		if file_path.to_str() == Some("") {
 			continue;
 		}
 
-		if !file.header().entry_type().is_dir() {
-			fs::create_dir_all(path.join(&file_path).parent().unwrap())?;
-			file.unpack(path.join(file_path))?;
+		let mut file = fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.open(parent.join(name))?;
+		io::copy(&mut entry, &mut file)?;
+
+		#[cfg(unix)]
+		if let Some(mode) = entry.unix_mode() {
+			use std::os::unix::fs::PermissionsExt;
+
+			file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+		}
+	}
+
+	Ok(())
+}
+
+/// The components of an entry's path, without the first `strip_components` directories.
+fn relative_parts(path: &Path, strip_components: usize) -> io::Result<Vec<&OsStr>> {
+	let mut parts = vec![];
+	let mut stripped = 0;
+
+	for component in path.components() {
+		match component {
+			Component::Normal(_) if stripped < strip_components => stripped += 1,
+			Component::Normal(part) => parts.push(part),
+			Component::CurDir => {},
+			Component::ParentDir => return Err(rejected(path, "its path contains `..`")),
+			Component::RootDir | Component::Prefix(_) => {
+				return Err(rejected(path, "its path is absolute"))
+			},
+		}
+	}
+
+	Ok(parts)
+}
+
+/// Create the directories an entry is in, checking each one (after following any symlinks
+/// extracted before it) is still inside `root`. Returns the canonical path of the last one.
+fn create_dirs_inside(root: &Path, dirs: &[&OsStr], entry_path: &Path) -> io::Result<PathBuf> {
+	let mut dir = root.to_path_buf();
+
+	for part in dirs {
+		dir.push(part);
+		if dir.symlink_metadata().is_err() {
+			fs::create_dir(&dir)?;
+		}
+
+		dir = dir.canonicalize()?;
+		if !dir.starts_with(root) {
+			return Err(rejected(
+				entry_path,
+				"it's inside a symlink that points outside the release",
+			));
+		}
+	}
+
+	Ok(dir)
+}
+
+/// Check a symlink in `parent` can't point outside of `root`. Targets can only go up (with
+/// leading `..` components) as far as `root`, and can't go up again after going down, since
+/// the directories they go through could be symlinks themselves.
+fn check_link_target(root: &Path, parent: &Path, target: &Path) -> Result<(), &'static str> {
+	let mut depth = parent
+		.strip_prefix(root)
+		.map_or(0, |parent| parent.components().count());
+	let mut descended = false;
+
+	for component in target.components() {
+		match component {
+			Component::ParentDir if descended => {
+				return Err("its target goes back up after going down, which can't be checked")
+			},
+			Component::ParentDir => {
+				depth = depth
+					.checked_sub(1)
+					.ok_or("its target is outside the release")?;
+			},
+			Component::Normal(_) => descended = true,
+			Component::CurDir => {},
+			Component::RootDir | Component::Prefix(_) => {
+				return Err("its target is an absolute path")
+			},
 		}
 	}
 
 	Ok(())
 }
 
+fn rejected(path: &Path, reason: &str) -> io::Error {
+	io::Error::new(
+		io::ErrorKind::InvalidData,
+		format!("Refusing to extract {}: {reason}", path.display()),
+	)
+}
+
+/// The total size of the files in a directory, without following symlinks.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+	let mut size = 0;
+
+	for entry in fs::read_dir(path)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+
+		size += if metadata.is_dir() {
+			dir_size(&entry.path())?
+		} else {
+			metadata.len()
+		};
+	}
+
+	Ok(size)
+}
+
+/// Create a command that runs in `dir` with only the given environment, which is usually
+/// a site's [`command_env`](crate::config::Site::command_env).
+pub fn site_command<S: AsRef<OsStr>>(
+	program: S,
+	env: &[(OsString, OsString)],
+	dir: &Path,
+) -> Command {
+	let mut command = Command::new(program);
+	command
+		.env_clear()
+		.envs(env.iter().map(|(key, value)| (key, value)))
+		.current_dir(dir);
+
+	command
+}
+
 pub fn spawn_with_logs(cmd: &mut Command) -> impl Stream<Item = io::Result<Log>> {
 	let process = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
 	let pretty_cmd = pretty_cmd(cmd.as_std());
@@ -150,10 +388,9 @@ pub fn spawn_with_logs(cmd: &mut Command) -> impl Stream<Item = io::Result<Log>>
 
 		let status = process.wait().await?;
 		if !status.success() {
-			return Err(io::Error::new(
-				io::ErrorKind::Other,
-				format!("Command failed with status: {status}"),
-			));
+			return Err(io::Error::other(format!(
+				"Command failed with status: {status}"
+			)));
 		}
 
 		Ok(())
@@ -170,3 +407,204 @@ fn pretty_cmd(cmd: &std::process::Command) -> Option<String> {
 
 	Some(format!("$ {bin} {args}"))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::{symlink, PermissionsExt};
+	use tar::{Builder, Header};
+	use tempfile::TempDir;
+	use zip::{write::SimpleFileOptions, ZipWriter};
+
+	/// Append an entry to a tarball, writing its path and link name as-is (unlike
+	/// [`Builder::append_data`], which refuses unsafe paths).
+	fn append(tar: &mut Builder<Vec<u8>>, kind: EntryType, path: &str, link: &str, data: &[u8]) {
+		let mut header = Header::new_old();
+		header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+		header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+		header.set_entry_type(kind);
+		header.set_mode(0o644);
+		header.set_size(data.len() as u64);
+		header.set_cksum();
+
+		tar.append(&header, data).unwrap();
+	}
+
+	/// Extract a tarball built by `build` into a fresh directory, stripping the first component.
+	fn untar(build: impl FnOnce(&mut Builder<Vec<u8>>)) -> (TempDir, io::Result<()>) {
+		let mut tar = Builder::new(Vec::new());
+		build(&mut tar);
+		let tar = tar.into_inner().unwrap();
+
+		let dir = TempDir::new().unwrap();
+		let result = untar_to(
+			tar::Archive::new(tar.as_slice()),
+			&dir.path().join("release"),
+			1,
+		);
+
+		(dir, result)
+	}
+
+	fn assert_rejected(result: io::Result<()>) {
+		let error = result.unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{error}");
+	}
+
+	#[test]
+	fn untar_keeps_permissions_and_symlinks() {
+		let (dir, result) = untar(|tar| {
+			let mut header = Header::new_gnu();
+			header.set_size(2);
+			header.set_mode(0o4755);
+			tar.append_data(&mut header, "repo/bin/run", &b"hi"[..])
+				.unwrap();
+
+			append(tar, EntryType::Symlink, "repo/run", "bin/run", &[]);
+			append(tar, EntryType::Symlink, "repo/bin/self", "../bin", &[]);
+		});
+		result.unwrap();
+
+		let release = dir.path().join("release");
+		let mode = fs::metadata(release.join("bin/run"))
+			.unwrap()
+			.permissions()
+			.mode();
+		assert_eq!(mode & 0o7777, 0o755);
+		assert_eq!(
+			fs::read_link(release.join("run")).unwrap(),
+			Path::new("bin/run")
+		);
+		assert_eq!(fs::read(release.join("bin/self/run")).unwrap(), b"hi");
+	}
+
+	#[test]
+	fn untar_rejects_parent_dirs() {
+		let (dir, result) = untar(|tar| {
+			append(tar, EntryType::Regular, "repo/../escape", "", b"hi");
+		});
+
+		assert_rejected(result);
+		assert!(!dir.path().join("escape").exists());
+	}
+
+	#[test]
+	fn untar_rejects_absolute_paths() {
+		let (_dir, result) = untar(|tar| {
+			append(tar, EntryType::Regular, "/repo/escape", "", b"hi");
+		});
+
+		assert_rejected(result);
+	}
+
+	#[test]
+	fn untar_rejects_symlinks_outside_the_tree() {
+		for target in ["../escape", "../../escape", "/etc", "dir/../../escape"] {
+			let (_dir, result) = untar(|tar| {
+				append(tar, EntryType::Symlink, "repo/link", target, &[]);
+			});
+
+			assert_rejected(result);
+		}
+	}
+
+	#[test]
+	fn untar_rejects_files_written_through_symlinks() {
+		let outside = TempDir::new().unwrap();
+		let target = outside.path().to_str().unwrap().to_string();
+
+		let (_dir, result) = untar(|tar| {
+			append(tar, EntryType::Symlink, "repo/link", &target, &[]);
+			append(tar, EntryType::Regular, "repo/link/escape", "", b"hi");
+		});
+		assert_rejected(result);
+
+		// a symlink that was already there, rather than in the tarball
+		let mut tar = Builder::new(Vec::new());
+		append(&mut tar, EntryType::Regular, "repo/link/escape", "", b"hi");
+		let tar = tar.into_inner().unwrap();
+
+		let dir = TempDir::new().unwrap();
+		symlink(outside.path(), dir.path().join("link")).unwrap();
+		assert_rejected(untar_to(tar::Archive::new(tar.as_slice()), dir.path(), 1));
+
+		assert!(!outside.path().join("escape").exists());
+	}
+
+	#[test]
+	fn untar_rejects_hard_links_and_devices() {
+		for kind in [
+			EntryType::Link,
+			EntryType::Char,
+			EntryType::Block,
+			EntryType::Fifo,
+		] {
+			let (_dir, result) = untar(|tar| {
+				append(tar, kind, "repo/entry", "repo/other", &[]);
+			});
+
+			assert_rejected(result);
+		}
+	}
+
+	/// Extract a zip archive built by `build` into a fresh directory.
+	fn unzip(
+		build: impl FnOnce(&mut ZipWriter<io::Cursor<Vec<u8>>>) -> io::Result<()>,
+	) -> (TempDir, io::Result<()>) {
+		let mut zip = ZipWriter::new(io::Cursor::new(Vec::new()));
+		build(&mut zip).unwrap();
+		let zip = zip.finish().unwrap();
+
+		let dir = TempDir::new().unwrap();
+		let result = unzip_to(
+			io::Cursor::new(zip.into_inner()),
+			&dir.path().join("release"),
+		);
+
+		(dir, result)
+	}
+
+	#[test]
+	fn unzip_keeps_permissions_and_symlinks() {
+		let (dir, result) = unzip(|zip| {
+			zip.start_file(
+				"bin/run",
+				SimpleFileOptions::default().unix_permissions(0o4755),
+			)?;
+			io::Write::write_all(zip, b"hi")?;
+			Ok(zip.add_symlink("run", "bin/run", SimpleFileOptions::default())?)
+		});
+		result.unwrap();
+
+		let release = dir.path().join("release");
+		let mode = fs::metadata(release.join("bin/run"))
+			.unwrap()
+			.permissions()
+			.mode();
+		assert_eq!(mode & 0o7777, 0o755);
+		assert_eq!(fs::read(release.join("run")).unwrap(), b"hi");
+	}
+
+	#[test]
+	fn unzip_rejects_unsafe_entries() {
+		let (dir, result) = unzip(|zip| {
+			zip.start_file("../escape", SimpleFileOptions::default())?;
+			io::Write::write_all(zip, b"hi")
+		});
+		assert_rejected(result);
+		assert!(!dir.path().join("escape").exists());
+
+		let (_dir, result) = unzip(|zip| {
+			zip.start_file("/escape", SimpleFileOptions::default())?;
+			io::Write::write_all(zip, b"hi")
+		});
+		assert_rejected(result);
+
+		let (_dir, result) = unzip(|zip| {
+			zip.add_symlink("link", "../escape", SimpleFileOptions::default())?;
+			zip.start_file("link/file", SimpleFileOptions::default())?;
+			io::Write::write_all(zip, b"hi")
+		});
+		assert_rejected(result);
+	}
+}
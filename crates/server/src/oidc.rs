@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use jsonwebtoken::{
+	decode, decode_header,
+	jwk::{Jwk, JwkSet},
+	Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+use crate::config::Oidc;
+
+/// How long fetched keys are trusted before being refreshed.
+const KEYS_TTL: Duration = Duration::from_hours(1);
+
+/// The minimum time between refreshes triggered by an unknown key id.
+const REFRESH_COOLDOWN: Duration = Duration::from_mins(1);
+
+/// The claims we care about in a GitHub Actions OIDC token.
+/// See <https://docs.github.com/en/actions/security-for-github-actions/security-hardening-your-deployments/about-security-hardening-with-openid-connect>.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Claims {
+	/// Identifies the workflow, like `repo:owner/repo:ref:refs/heads/main`.
+	pub sub: String,
+	/// The repository the workflow runs in, as `owner/repo`.
+	pub repository: String,
+	/// The git ref the workflow runs for.
+	#[serde(rename = "ref")]
+	pub r#ref: String,
+	/// The environment the job targets, if any.
+	pub environment: Option<String>,
+}
+
+/// Whether the given value looks like a JWT rather than a static token.
+pub fn is_jwt(token: &str) -> bool {
+	token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+/// Verifies OIDC tokens, caching the issuer's signing keys.
+#[derive(Debug, Default)]
+pub struct Verifier {
+	keys: RwLock<HashMap<String, (JwkSet, Instant)>>,
+}
+
+impl Verifier {
+	/// Check the token's signature, issuer, audience and expiry, returning its claims.
+	pub async fn verify(&self, config: &Oidc, token: &str) -> Result<Claims> {
+		let header = decode_header(token).context("Invalid JWT header")?;
+		if header.alg != Algorithm::RS256 {
+			bail!("Unsupported JWT algorithm {:?}", header.alg);
+		}
+
+		let kid = header.kid.context("JWT is missing a key id")?;
+		let jwk = self.key(config, &kid).await?;
+
+		let mut validation = Validation::new(Algorithm::RS256);
+		validation.set_issuer(&[&config.issuer]);
+		validation.set_audience(&[&config.audience]);
+
+		let data = decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+
+		Ok(data.claims)
+	}
+
+	/// Find the key with the given id, fetching the key set again if it's stale or doesn't contain it.
+	async fn key(&self, config: &Oidc, kid: &str) -> Result<Jwk> {
+		let source = config.jwks_source();
+
+		if let Some((keys, fetched_at)) = self.keys.read().await.get(&source) {
+			if let Some(jwk) = keys.find(kid).filter(|_| fetched_at.elapsed() < KEYS_TTL) {
+				return Ok(jwk.clone());
+			}
+
+			if fetched_at.elapsed() < REFRESH_COOLDOWN {
+				bail!("Unknown JWT key id {kid}");
+			}
+		}
+
+		let keys = fetch_keys(config).await?;
+		let jwk = keys.find(kid).cloned();
+		self.keys
+			.write()
+			.await
+			.insert(source, (keys, Instant::now()));
+
+		jwk.with_context(|| format!("Unknown JWT key id {kid}"))
+	}
+}
+
+async fn fetch_keys(config: &Oidc) -> Result<JwkSet> {
+	if let Some(path) = &config.jwks_file {
+		let file = tokio::fs::read_to_string(path)
+			.await
+			.with_context(|| format!("Could not read JWKS file {}", path.display()))?;
+
+		return Ok(serde_json::from_str(&file)?);
+	}
+
+	Ok(reqwest::Client::builder()
+		.user_agent("orbit-server")
+		.build()?
+		.get(config.jwks_source())
+		.send()
+		.await?
+		.error_for_status()?
+		.json()
+		.await?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Site;
+	use chrono::Utc;
+	use jsonwebtoken::{encode, errors::ErrorKind, EncodingKey, Header};
+	use serde_json::json;
+
+	const KEY: &str = include_str!("../tests/fixtures/oidc.pem");
+	const JWKS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/oidc.jwks.json");
+
+	fn config() -> Oidc {
+		Oidc {
+			audience: "orbit".to_string(),
+			issuer: "https://token.actions.githubusercontent.com".to_string(),
+			jwks_url: None,
+			jwks_file: Some(JWKS.into()),
+		}
+	}
+
+	/// The claims of a workflow on the `main` branch of `owner/repo`, with some of them overridden.
+	fn workflow(overrides: serde_json::Value) -> serde_json::Value {
+		let mut claims = json!({
+			"sub": "repo:owner/repo:ref:refs/heads/main",
+			"repository": "owner/repo",
+			"ref": "refs/heads/main",
+			"environment": "production",
+			"aud": "orbit",
+			"iss": "https://token.actions.githubusercontent.com",
+			"exp": Utc::now().timestamp() + 300,
+		});
+
+		let serde_json::Value::Object(overrides) = overrides else {
+			panic!("overrides must be an object");
+		};
+		for (name, value) in overrides {
+			claims[name] = value;
+		}
+
+		claims
+	}
+
+	/// Sign a workflow's token with the test key, under the given key id.
+	fn token(kid: &str, overrides: serde_json::Value) -> String {
+		let mut header = Header::new(Algorithm::RS256);
+		header.kid = Some(kid.to_string());
+
+		encode(
+			&header,
+			&workflow(overrides),
+			&EncodingKey::from_rsa_pem(KEY.as_bytes()).unwrap(),
+		)
+		.unwrap()
+	}
+
+	fn claims(overrides: serde_json::Value) -> Claims {
+		serde_json::from_value(workflow(overrides)).unwrap()
+	}
+
+	fn site(oidc: &str) -> Site {
+		toml::from_str(&format!(
+			"name = \"Site\"\npath = \"/srv/site\"\ngithub_repo = \"owner/repo\"\noidc = {oidc}"
+		))
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn accepts_valid_tokens() {
+		let claims = Verifier::default()
+			.verify(&config(), &token("test", json!({})))
+			.await
+			.unwrap();
+
+		assert_eq!(claims.repository, "owner/repo");
+		assert_eq!(claims.r#ref, "refs/heads/main");
+		assert_eq!(claims.environment.as_deref(), Some("production"));
+	}
+
+	/// Verify a token signed with the test key, returning why it was rejected.
+	async fn rejection(overrides: serde_json::Value) -> ErrorKind {
+		let error = Verifier::default()
+			.verify(&config(), &token("test", overrides))
+			.await
+			.unwrap_err();
+
+		error
+			.downcast_ref::<jsonwebtoken::errors::Error>()
+			.unwrap_or_else(|| panic!("unexpected error: {error:#}"))
+			.kind()
+			.clone()
+	}
+
+	#[tokio::test]
+	async fn rejects_tokens_for_other_audiences_or_issuers() {
+		assert_eq!(
+			rejection(json!({ "aud": "someone-else" })).await,
+			ErrorKind::InvalidAudience
+		);
+		assert_eq!(
+			rejection(json!({ "iss": "https://example.com" })).await,
+			ErrorKind::InvalidIssuer
+		);
+	}
+
+	#[tokio::test]
+	async fn rejects_expired_tokens() {
+		assert_eq!(
+			rejection(json!({ "exp": Utc::now().timestamp() - 600 })).await,
+			ErrorKind::ExpiredSignature
+		);
+	}
+
+	#[tokio::test]
+	async fn rejects_unknown_keys_and_algorithms() {
+		let verifier = Verifier::default();
+		assert!(verifier
+			.verify(&config(), &token("other", json!({})))
+			.await
+			.is_err());
+
+		let mut header = Header::new(Algorithm::HS256);
+		header.kid = Some("test".to_string());
+		let token = encode(
+			&header,
+			&json!({ "aud": "orbit", "exp": Utc::now().timestamp() + 300 }),
+			&EncodingKey::from_secret(b"secret"),
+		)
+		.unwrap();
+		assert!(verifier.verify(&config(), &token).await.is_err());
+	}
+
+	#[test]
+	fn checks_the_repository() {
+		let site = site("{}");
+		let rules = site.oidc.as_ref().unwrap();
+
+		assert!(rules.allows(&site, &claims(json!({}))));
+		assert!(rules.allows(&site, &claims(json!({ "repository": "Owner/Repo" }))));
+		assert!(!rules.allows(&site, &claims(json!({ "repository": "owner/fork" }))));
+
+		let site = self::site("{ repository = \"owner/other\" }");
+		let rules = site.oidc.as_ref().unwrap();
+		assert!(!rules.allows(&site, &claims(json!({}))));
+		assert!(rules.allows(&site, &claims(json!({ "repository": "owner/other" }))));
+	}
+
+	#[test]
+	fn checks_the_ref() {
+		let site = site("{ refs = [\"refs/heads/main\", \"refs/tags/v*\"] }");
+		let rules = site.oidc.as_ref().unwrap();
+
+		assert!(rules.allows(&site, &claims(json!({}))));
+		assert!(rules.allows(&site, &claims(json!({ "ref": "refs/tags/v1.2.0" }))));
+		assert!(!rules.allows(&site, &claims(json!({ "ref": "refs/heads/feature" }))));
+		assert!(!rules.allows(&site, &claims(json!({ "ref": "refs/heads/main-2" }))));
+	}
+
+	#[test]
+	fn checks_the_environment() {
+		let site = site("{ environments = [\"production\"] }");
+		let rules = site.oidc.as_ref().unwrap();
+
+		assert!(rules.allows(&site, &claims(json!({}))));
+		assert!(!rules.allows(&site, &claims(json!({ "environment": "staging" }))));
+		assert!(!rules.allows(&site, &claims(json!({ "environment": null }))));
+	}
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, RwLock},
+	time::{Duration, SystemTime},
+};
+
+use crate::config::Config;
+
+/// How often the config file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Holds the live config, which can be swapped for a new one without restarting the server.
+/// Requests see the config that was current when they started, and deployments keep the
+/// `Site` they were started with, so reloading never affects work in progress.
+///
+/// The addresses the server listens on, its TLS certificate paths and the audit log location
+/// are only read at startup, and need a restart to change.
+pub struct Reloader {
+	path: PathBuf,
+	current: RwLock<Arc<Config>>,
+	/// When the config file and the files it includes were last modified, as of the last reload.
+	modified: Mutex<Vec<(PathBuf, Option<SystemTime>)>>,
+	/// Held while reloading, so reloads triggered from different places don't race.
+	reloading: Mutex<()>,
+}
+
+impl Reloader {
+	pub fn new(path: PathBuf, config: Config) -> Self {
+		Self {
+			modified: Mutex::new(modified(&path, &config)),
+			current: RwLock::new(Arc::new(config)),
+			reloading: Mutex::new(()),
+			path,
+		}
+	}
+
+	/// The current config.
+	pub fn get(&self) -> Arc<Config> {
+		self.current.read().unwrap().clone()
+	}
+
+	/// Load and validate the config file again, and swap it in if it's valid.
+	/// If it isn't, the previous config stays in place.
+	/// This blocks on reading the files, so call it from a blocking task.
+	pub fn reload(&self) -> Result<()> {
+		let _reloading = self.reloading.lock().unwrap();
+
+		let config = match Config::load(&self.path).and_then(Config::validate) {
+			Ok(config) => config,
+			Err(e) => {
+				// don't retry until the files change again
+				*self.modified.lock().unwrap() = modified(&self.path, &self.get());
+				return Err(e);
+			},
+		};
+
+		let last_modified = modified(&self.path, &config);
+		*self.current.write().unwrap() = Arc::new(config);
+		*self.modified.lock().unwrap() = last_modified;
+
+		tracing::info!("Reloaded config from {}", self.path.display());
+
+		Ok(())
+	}
+
+	/// Whether the config file or the files it includes changed since the last reload.
+	fn changed(&self) -> bool {
+		let current = modified(&self.path, &self.get());
+
+		current != *self.modified.lock().unwrap()
+	}
+
+	/// Reload the config whenever its files change or the server receives a `SIGHUP`.
+	pub async fn watch(self: Arc<Self>) {
+		#[cfg(unix)]
+		let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+		let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+		loop {
+			#[cfg(unix)]
+			let signalled = tokio::select! {
+				_ = interval.tick() => false,
+				Some(()) = async { hangup.as_mut()?.recv().await } => true,
+			};
+			#[cfg(not(unix))]
+			let signalled = {
+				interval.tick().await;
+				false
+			};
+
+			let reloader = self.clone();
+			let result = tokio::task::spawn_blocking(move || {
+				if signalled || reloader.changed() {
+					reloader.reload()
+				} else {
+					Ok(())
+				}
+			});
+
+			match result.await {
+				Ok(Ok(())) => {},
+				Ok(Err(e)) => tracing::error!("Failed to reload config: {e:#}"),
+				Err(e) => tracing::error!(e = ?e, "Failed to reload config"),
+			}
+		}
+	}
+}
+
+/// Make the current config available to the request as an `Extension<Arc<Config>>`.
+pub async fn current(
+	Extension(reloader): Extension<Arc<Reloader>>,
+	mut request: Request,
+	next: Next,
+) -> Response {
+	request.extensions_mut().insert(reloader.get());
+
+	next.run(request).await
+}
+
+/// When the config file at `path` and the files it includes were last modified.
+fn modified(path: &Path, config: &Config) -> Vec<(PathBuf, Option<SystemTime>)> {
+	let included = config.included_files(path).unwrap_or_default();
+
+	std::iter::once(path.to_path_buf())
+		.chain(included)
+		.map(|file| {
+			let modified = fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+			(file, modified)
+		})
+		.collect()
+}
@@ -0,0 +1,96 @@
+use std::{io, sync::Arc};
+
+use aide::axum::{routing::get, ApiRouter};
+use axum::{extract::Query, http::StatusCode, Extension};
+use axum_jsonschema::Json;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+	audit::{Action, Audit, AuditLog, Entry, Outcome},
+	auth::Auth,
+	config::{Config, Scope},
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().api_route("/audit", get(list_entries))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AuditParams {
+	/// Only include entries for the site with this slug.
+	site: Option<String>,
+	/// Only include entries by the token with this name.
+	actor: Option<String>,
+	/// Only include this action, like `deploy` or `env_change`.
+	action: Option<String>,
+	/// Only include entries from this time onwards, as an RFC 3339 timestamp.
+	since: Option<DateTime<Utc>>,
+	/// Only include entries before this time, as an RFC 3339 timestamp.
+	until: Option<DateTime<Utc>>,
+	/// The maximum number of entries to return. Defaults to 100.
+	limit: Option<usize>,
+}
+
+/// List the entries in the audit log, newest first. Requires the `admin` scope, and only includes
+/// entries for sites the token can access (or every entry, for tokens that can access every site).
+pub async fn list_entries(
+	Query(params): Query<AuditParams>,
+	Extension(config): Extension<Arc<Config>>,
+	Extension(log): Extension<Arc<AuditLog>>,
+	auth: Auth,
+	audit: Audit,
+) -> Result<Json<Vec<Entry>>, StatusCode> {
+	if !auth.has_scope(Scope::Admin) {
+		if let Err(e) = audit.record_global(Some(&auth), Action::AuditRead, Outcome::Denied) {
+			tracing::error!(e = ?e, "Failed to write audit log");
+		}
+
+		return Err(StatusCode::FORBIDDEN);
+	}
+
+	let entries = match log.entries() {
+		Ok(entries) => entries,
+		Err(e) => return audit.finish_global(&auth, Action::AuditRead, Err(internal_error(e))),
+	};
+	// entries that aren't about a single site are only shown to tokens that can see every site
+	let visible = |entry: &Entry| {
+		entry.site.as_ref().map_or_else(
+			|| auth.can_access_all(),
+			|slug| config.site(slug).is_some_and(|site| auth.can_access(&site)),
+		)
+	};
+
+	let entries = entries
+		.into_iter()
+		.rev()
+		.filter(|entry| {
+			params
+				.site
+				.as_ref()
+				.is_none_or(|site| entry.site.as_ref() == Some(site))
+				&& params
+					.actor
+					.as_ref()
+					.is_none_or(|actor| entry.actor.as_ref() == Some(actor))
+				&& params
+					.action
+					.as_ref()
+					.is_none_or(|action| entry.action.name() == action)
+				&& params.since.is_none_or(|since| entry.at >= since)
+				&& params.until.is_none_or(|until| entry.at < until)
+				&& visible(entry)
+		})
+		.take(params.limit.unwrap_or(100))
+		.collect();
+
+	audit.finish_global(&auth, Action::AuditRead, Ok(Json(entries)))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn internal_error(error: io::Error) -> StatusCode {
+	tracing::error!(e = ?error);
+
+	StatusCode::INTERNAL_SERVER_ERROR
+}
@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use aide::axum::{routing::post, ApiRouter};
+use axum::{http::StatusCode, Extension};
+
+use crate::{
+	audit::{Action, Audit, Outcome},
+	auth::Auth,
+	config::Scope,
+	reload::Reloader,
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().api_route("/config/reload", post(reload_config))
+}
+
+/// Reload the config file, swapping it in if it's valid. Requires the `admin` scope, on a token
+/// that can access every site, since the reload applies to all of them. Deployments in progress
+/// keep running with the config they started with.
+pub async fn reload_config(
+	Extension(reloader): Extension<Arc<Reloader>>,
+	auth: Auth,
+	audit: Audit,
+) -> Result<StatusCode, (StatusCode, String)> {
+	if !(auth.has_scope(Scope::Admin) && auth.can_access_all()) {
+		if let Err(e) = audit.record_global(Some(&auth), Action::ConfigReload, Outcome::Denied) {
+			tracing::error!(e = ?e, "Failed to write audit log");
+		}
+
+		return Err((StatusCode::FORBIDDEN, String::new()));
+	}
+
+	tracing::info!(token = auth.name, "Reloading config");
+
+	let result = match tokio::task::spawn_blocking(move || reloader.reload()).await {
+		Ok(Ok(())) => Ok(StatusCode::NO_CONTENT),
+		Ok(Err(e)) => {
+			tracing::error!("Failed to reload config: {e:#}");
+			Err((StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}")))
+		},
+		Err(e) => {
+			tracing::error!(e = ?e, "Failed to reload config");
+			Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()))
+		},
+	};
+
+	let status = result.as_ref().map(|_| ()).map_err(|(status, _)| *status);
+	audit
+		.finish_global(&auth, Action::ConfigReload, status)
+		.map_err(|status| (status, String::new()))?;
+
+	result
+}
@@ -1,5 +1,8 @@
 use aide::{axum::ApiRouter, openapi::OpenApi, scalar::Scalar};
 use axum::{routing::get, Extension, Json};
+use schemars::schema::RootSchema;
+
+use crate::config::Config;
 
 pub fn handler() -> ApiRouter {
 	let scalar = Scalar::new("/openapi.json").with_title("Orbit Docs");
@@ -7,9 +10,16 @@ pub fn handler() -> ApiRouter {
 	ApiRouter::new()
 		.route("/docs", scalar.axum_route())
 		.route("/openapi.json", get(openapi_schema))
+		.route("/config/schema", get(config_schema))
 }
 
 #[allow(clippy::unused_async)]
 async fn openapi_schema(Extension(openapi): Extension<OpenApi>) -> Json<OpenApi> {
 	Json(openapi)
 }
+
+/// The JSON Schema of the config file, for editors to validate and autocomplete it with.
+#[allow(clippy::unused_async)]
+async fn config_schema() -> Json<RootSchema> {
+	Json(Config::schema())
+}
@@ -0,0 +1,208 @@
+use std::{collections::BTreeMap, io, sync::Arc};
+
+use aide::axum::{routing::get, ApiRouter};
+use axum::{
+	extract::{Path, Query},
+	http::StatusCode,
+	Extension,
+};
+use axum_jsonschema::Json;
+use futures_util::{pin_mut, StreamExt};
+use orbit_types::{CommandOutput, EnvResponse, EnvUpdate, Log};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::{
+	audit::{Action, Audit},
+	auth::Auth,
+	config::{Config, Scope, Site},
+	dotenv::{self, DotEnv, Locks},
+	misc::{site_command, spawn_with_logs},
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().api_route(
+		"/sites/:site/env",
+		get(show_env).put(replace_env).patch(update_env),
+	)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct EnvParams {
+	/// Run `php artisan config:cache` on the live deployment after applying the changes.
+	#[serde(default)]
+	config_cache: bool,
+}
+
+/// Read the variables in the site's `.env` file, with their values masked.
+pub async fn show_env(
+	Path(site_id): Path<String>,
+	Extension(config): Extension<Arc<Config>>,
+	auth: Auth,
+	audit: Audit,
+) -> Result<Json<EnvResponse>, StatusCode> {
+	let site = config.site(&site_id).ok_or(StatusCode::NOT_FOUND)?;
+	audit.authorize(&auth, &site, Scope::Read, &Action::EnvRead)?;
+
+	let result = DotEnv::load(site.path.join(".env"))
+		.map_err(internal_error)
+		.map(|env| {
+			Json(EnvResponse {
+				env: masked(&env),
+				config_cache: None,
+			})
+		});
+
+	audit.finish(&auth, &site, Action::EnvRead, result)
+}
+
+/// Replace the contents of the site's `.env` file.
+pub async fn replace_env(
+	Path(site_id): Path<String>,
+	Query(params): Query<EnvParams>,
+	Extension(config): Extension<Arc<Config>>,
+	auth: Auth,
+	audit: Audit,
+	Extension(locks): Extension<Arc<Locks>>,
+	Json(vars): Json<BTreeMap<String, String>>,
+) -> Result<Json<EnvResponse>, StatusCode> {
+	let site = config.site(&site_id).ok_or(StatusCode::NOT_FOUND)?;
+	let action = Action::EnvReplace {
+		keys: vars.keys().cloned().collect(),
+	};
+	audit.authorize(&auth, &site, Scope::Admin, &action)?;
+
+	let result = async {
+		if !vars.keys().all(|key| dotenv::is_valid_key(key)) {
+			return Err(StatusCode::UNPROCESSABLE_ENTITY);
+		}
+
+		let lock = locks.lock(&site).await;
+		let mut env = DotEnv::load(site.path.join(".env")).map_err(internal_error)?;
+		env.clear();
+		for (key, value) in &vars {
+			env.set(key, value);
+		}
+
+		save(&site, &env)?;
+		drop(lock);
+
+		respond(&site, &env, params.config_cache).await
+	}
+	.await;
+
+	audit.finish(&auth, &site, action, result)
+}
+
+/// Add, overwrite or remove individual variables in the site's `.env` file.
+pub async fn update_env(
+	Path(site_id): Path<String>,
+	Query(params): Query<EnvParams>,
+	Extension(config): Extension<Arc<Config>>,
+	auth: Auth,
+	audit: Audit,
+	Extension(locks): Extension<Arc<Locks>>,
+	Json(update): Json<EnvUpdate>,
+) -> Result<Json<EnvResponse>, StatusCode> {
+	let site = config.site(&site_id).ok_or(StatusCode::NOT_FOUND)?;
+	let action = Action::EnvChange {
+		set: update.set.keys().cloned().collect(),
+		unset: update.unset.clone(),
+	};
+	audit.authorize(&auth, &site, Scope::Admin, &action)?;
+
+	let result = async {
+		if !update.set.keys().all(|key| dotenv::is_valid_key(key)) {
+			return Err(StatusCode::UNPROCESSABLE_ENTITY);
+		}
+
+		let lock = locks.lock(&site).await;
+		let mut env = DotEnv::load(site.path.join(".env")).map_err(internal_error)?;
+		for (key, value) in &update.set {
+			env.set(key, value);
+		}
+		for key in &update.unset {
+			env.unset(key);
+		}
+
+		save(&site, &env)?;
+		drop(lock);
+
+		respond(&site, &env, params.config_cache).await
+	}
+	.await;
+
+	audit.finish(&auth, &site, action, result)
+}
+
+fn save(site: &Site, env: &DotEnv) -> Result<(), StatusCode> {
+	env.save().map_err(internal_error)?;
+
+	// deployments only link the `.env` file if it existed when they were created
+	let current_env = site.path.join("current/.env");
+	if site.path.join("current").exists() && current_env.symlink_metadata().is_err() {
+		symlink::symlink_file(env.path(), current_env).map_err(internal_error)?;
+	}
+
+	Ok(())
+}
+
+async fn respond(
+	site: &Site,
+	env: &DotEnv,
+	config_cache: bool,
+) -> Result<Json<EnvResponse>, StatusCode> {
+	let config_cache = if config_cache {
+		Some(cache_config(site).await.map_err(internal_error)?)
+	} else {
+		None
+	};
+
+	Ok(Json(EnvResponse {
+		config_cache,
+		env: masked(env),
+	}))
+}
+
+async fn cache_config(site: &Site) -> io::Result<CommandOutput> {
+	let stream = spawn_with_logs(
+		site_command("php", &site.command_env()?, &site.path.join("current"))
+			.arg("artisan")
+			.arg("config:cache"),
+	);
+	pin_mut!(stream);
+
+	let redactor = site.redactor();
+	let mut logs = vec![];
+	while let Some(log) = stream.next().await {
+		match log {
+			Ok(log) => logs.push(redactor.redact_log(log)),
+			Err(e) => {
+				logs.push(Log::Error(e.to_string()));
+
+				return Ok(CommandOutput {
+					logs,
+					success: false,
+				});
+			},
+		}
+	}
+
+	Ok(CommandOutput {
+		logs,
+		success: true,
+	})
+}
+
+fn masked(env: &DotEnv) -> BTreeMap<String, String> {
+	env.keys()
+		.map(|key| (key.to_string(), "********".to_string()))
+		.collect()
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn internal_error(error: io::Error) -> StatusCode {
+	tracing::error!(e = ?error);
+
+	StatusCode::INTERNAL_SERVER_ERROR
+}
@@ -1,12 +1,29 @@
 use aide::axum::ApiRouter;
+use axum::middleware;
 
+use crate::{access, signing};
+
+mod audit;
+mod config;
 mod docs;
+mod env;
 mod sites;
 mod system;
 
 pub fn handler() -> ApiRouter {
 	ApiRouter::new()
 		.merge(docs::handler())
-		.merge(sites::handler())
 		.merge(system::handler())
+		.merge(api())
+		.route_layer(middleware::from_fn(access::check))
+}
+
+/// Routes that act on sites, which require authentication.
+fn api() -> ApiRouter {
+	ApiRouter::new()
+		.merge(audit::handler())
+		.merge(config::handler())
+		.merge(env::handler())
+		.merge(sites::handler())
+		.route_layer(middleware::from_fn(signing::verify))
 }
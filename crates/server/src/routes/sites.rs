@@ -1,58 +1,220 @@
 use std::{convert::Infallible, sync::Arc};
 
-use aide::axum::{routing::post, ApiRouter};
+use aide::axum::{
+	routing::{get, post},
+	ApiRouter,
+};
 use axum::{
+	body::Body,
 	extract::{Path, Query},
-	http::StatusCode,
+	http::{header, HeaderMap, StatusCode},
 	response::sse::{Event, KeepAlive},
 	Extension,
 };
-use axum_extra::{
-	headers::{authorization::Bearer, Authorization},
-	TypedHeader,
-};
+use axum_jsonschema::Json;
 use futures_util::{stream::Stream, StreamExt};
-use orbit_types::{ErrorResponse, Progress};
+use orbit_types::{ErrorResponse, Progress, SiteStatus, SiteSummary, Stage, CONTENT_SHA256_HEADER};
 use schemars::JsonSchema;
 use serde::Deserialize;
 
 use crate::{
-	config::{Config, SiteCollectionExt},
+	audit::{Action, Audit, Outcome},
+	auth::Auth,
+	config::{Config, GitHubBuild, Scope},
+	deploy::{self, Artifact, Build, Tracker},
+	github::TokenCache,
 	misc::Sse,
 };
 
 pub fn handler() -> ApiRouter {
-	ApiRouter::new().api_route("/sites/:site/deploy", post(deploy_site))
+	ApiRouter::new()
+		.api_route("/sites", get(list_sites))
+		.api_route("/sites/:site", get(site_status))
+		.api_route("/sites/:site/deploy", post(deploy_site))
+}
+
+/// List the sites the token can access, along with their live deployment.
+pub async fn list_sites(
+	Extension(config): Extension<Arc<Config>>,
+	auth: Auth,
+	audit: Audit,
+) -> Result<Json<Vec<SiteSummary>>, StatusCode> {
+	let sites = config
+		.sites
+		.iter()
+		.filter(|site| auth.can_access(site))
+		.cloned()
+		.collect::<Vec<_>>();
+
+	let result = tokio::task::spawn_blocking(move || sites.iter().map(deploy::summary).collect())
+		.await
+		.map(Json)
+		.map_err(|e| {
+			tracing::error!(e = ?e);
+			StatusCode::INTERNAL_SERVER_ERROR
+		});
+
+	audit.finish_global(&auth, Action::SitesList, result)
+}
+
+/// Report what's deployed on the site, what's currently deploying, and the last deployment that failed.
+pub async fn site_status(
+	Path(site_id): Path<String>,
+	Extension(config): Extension<Arc<Config>>,
+	Extension(tracker): Extension<Arc<Tracker>>,
+	auth: Auth,
+	audit: Audit,
+) -> Result<Json<SiteStatus>, StatusCode> {
+	let site = config.site(&site_id).ok_or(StatusCode::NOT_FOUND)?;
+	audit.authorize(&auth, &site, Scope::Read, &Action::StatusRead)?;
+
+	let running = tracker.running(&site);
+	let result = {
+		let site = site.clone();
+
+		tokio::task::spawn_blocking(move || deploy::status(&site, running))
+			.await
+			.map_err(|e| {
+				tracing::error!(e = ?e);
+				StatusCode::INTERNAL_SERVER_ERROR
+			})?
+			.map(Json)
+			.map_err(|e| {
+				tracing::error!(e = ?e);
+				StatusCode::INTERNAL_SERVER_ERROR
+			})
+	};
+
+	audit.finish(&auth, &site, Action::StatusRead, result)
 }
 
 #[derive(Debug, Deserialize, JsonSchema)]
 pub struct DeployConfig {
 	/// The Git reference to deploy. If not provided, the default branch will be used.
+	/// It must be a valid Git ref name.
 	r#ref: Option<String>,
+	/// Deploy the asset with this name from the release tagged `ref` (or the latest release), instead of the site's build.
+	release_asset: Option<String>,
+	/// Deploy the artifact with this name from a GitHub Actions workflow run, instead of the site's build.
+	workflow_artifact: Option<String>,
+	/// The workflow run to take the artifact from. Defaults to the latest one that built `ref`.
+	run_id: Option<u64>,
+	/// The SHA-256 checksum the release asset or workflow artifact must have, to deploy exactly the same build as somewhere else.
+	checksum: Option<String>,
 }
 
+/// Deploy the site from its repository or a build published on GitHub, or from an artifact uploaded as
+/// the body (a gzipped tarball or zip archive) with its SHA-256 checksum in the `X-Orbit-Content-SHA256` header.
+#[allow(clippy::too_many_arguments)]
 pub async fn deploy_site(
 	Path(site_id): Path<String>,
 	Query(params): Query<DeployConfig>,
 	Extension(config): Extension<Arc<Config>>,
-	TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+	Extension(tracker): Extension<Arc<Tracker>>,
+	Extension(tokens): Extension<Arc<TokenCache>>,
+	auth: Auth,
+	audit: Audit,
+	headers: HeaderMap,
+	body: Body,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
-	if authorization.token() != config.token {
-		return Err(StatusCode::UNAUTHORIZED);
+	let Some(site) = config.site(&site_id) else {
+		return Err(StatusCode::NOT_FOUND);
+	};
+
+	let checksum = headers
+		.get(CONTENT_SHA256_HEADER)
+		.map(|checksum| {
+			checksum
+				.to_str()
+				.ok()
+				.and_then(parse_checksum)
+				.ok_or(StatusCode::BAD_REQUEST)
+		})
+		.transpose()?;
+	let content_length = headers
+		.get(header::CONTENT_LENGTH)
+		.and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+
+	let build_checksum = params
+		.checksum
+		.as_deref()
+		.map(|checksum| parse_checksum(checksum).ok_or(StatusCode::BAD_REQUEST))
+		.transpose()?;
+	let build = match (params.release_asset, params.workflow_artifact) {
+		(Some(_), Some(_)) => return Err(StatusCode::BAD_REQUEST),
+		(Some(name), None) => Some(GitHubBuild::ReleaseAsset(name)),
+		(None, Some(name)) => Some(GitHubBuild::WorkflowArtifact(name)),
+		// uploaded artifacts take the place of the site's build
+		(None, None) if checksum.is_none() => site.build.clone(),
+		(None, None) => None,
+	};
+
+	let valid = match &build {
+		_ if checksum.is_some() => {
+			build.is_none() && build_checksum.is_none() && params.run_id.is_none()
+		},
+		Some(GitHubBuild::WorkflowArtifact(_)) => true,
+		Some(GitHubBuild::ReleaseAsset(_)) => params.run_id.is_none(),
+		None => build_checksum.is_none() && params.run_id.is_none(),
+	};
+	// the ref ends up in GitHub API paths, so it can't be allowed to reach other endpoints
+	if !valid
+		|| params
+			.r#ref
+			.as_deref()
+			.is_some_and(|r#ref| !deploy::is_valid_ref(r#ref))
+	{
+		return Err(StatusCode::BAD_REQUEST);
 	}
 
-	let Some(site) = config.sites.find(&site_id) else {
-		return Err(StatusCode::NOT_FOUND);
+	let action = Action::Deploy {
+		r#ref: params.r#ref.clone(),
+		artifact: checksum.clone(),
+		build: build.clone(),
 	};
+	audit.authorize(&auth, &site, Scope::Deploy, &action)?;
 
-	let stream = site
-		.deploy(params.r#ref)
+	if checksum.is_some() && content_length.is_some_and(|length| length > config.max_artifact_size)
+	{
+		return audit.finish(&auth, &site, action, Err(StatusCode::PAYLOAD_TOO_LARGE));
+	}
+	let mut pending = audit.start(&auth, &site, action);
+
+	let mut deployer = site.deploy(params.r#ref, auth.name, tokens);
+	if let Some(checksum) = checksum {
+		deployer = deployer.with_artifact(Artifact::new(
+			body,
+			content_length,
+			checksum,
+			config.max_artifact_size,
+		));
+	} else if let Some(build) = build {
+		deployer = deployer.with_build(Build::new(
+			build,
+			params.run_id,
+			build_checksum,
+			config.max_artifact_size,
+		));
+	}
+
+	let stream = deployer
+		.tracked_by(&tracker)
 		.stream()
-		.map(|result| match result {
+		.map(move |result| match result {
 			Ok(Progress::Log(log)) => Event::default().id("log").json_data(log).unwrap(),
-			Ok(Progress::Stage(stage)) => Event::default().id("stage").json_data(stage).unwrap(),
+			Ok(Progress::Download(progress)) => {
+				Event::default().id("download").json_data(progress).unwrap()
+			},
+			Ok(Progress::Stage(stage)) => {
+				if matches!(stage, Stage::Deployed) {
+					pending.set_outcome(Outcome::Success);
+				}
+
+				Event::default().id("stage").json_data(stage).unwrap()
+			},
 			Err(e) => {
 				tracing::error!(e = ?e);
+				pending.set_outcome(Outcome::Failed(e.to_string()));
 
 				Event::default()
 					.id("error")
@@ -64,3 +226,9 @@ pub async fn deploy_site(
 
 	Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
 }
+
+/// A hex-encoded SHA-256 checksum, normalized to lowercase.
+fn parse_checksum(checksum: &str) -> Option<String> {
+	(checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit()))
+		.then(|| checksum.to_lowercase())
+}
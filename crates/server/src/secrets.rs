@@ -0,0 +1,300 @@
+use aes_gcm::{
+	aead::{Aead, KeyInit, OsRng, Payload},
+	AeadCore, Aes256Gcm, Key, Nonce,
+};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::Subcommand;
+use indexmap::IndexMap;
+use std::{
+	env, fmt, fs,
+	io::{self, Read},
+	path::{Path, PathBuf},
+	process,
+};
+use toml_edit::{value, DocumentMut, Item, TableLike};
+
+/// The environment variable holding the key used to encrypt secrets.
+pub const KEY_ENV: &str = "ORBIT_SECRETS_KEY";
+
+/// Prefix for encrypted values, so we can change the scheme in the future.
+const VERSION_PREFIX: &str = "v1:";
+
+const NONCE_LEN: usize = 12;
+
+/// A decrypted secret. Its value is never printed when debugging.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+	pub fn expose(&self) -> &str {
+		&self.0
+	}
+}
+
+impl From<String> for Secret {
+	fn from(value: String) -> Self {
+		Self(value)
+	}
+}
+
+impl fmt::Debug for Secret {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("Secret(********)")
+	}
+}
+
+/// Encrypts and decrypts secrets with AES-256-GCM. The secret's name is used as associated data,
+/// so encrypted values can't be swapped between secrets.
+pub struct Cipher(Aes256Gcm);
+
+impl Cipher {
+	/// Load the key from `$ORBIT_SECRETS_KEY`, falling back to the given key file.
+	pub fn load(key_file: Option<&Path>) -> Result<Self> {
+		let key = match (env::var(KEY_ENV), key_file) {
+			(Ok(key), _) => key,
+			(Err(_), Some(path)) => fs::read_to_string(path)
+				.with_context(|| format!("Could not read secrets key file {}", path.display()))?,
+			(Err(_), None) => {
+				bail!("No secrets key found. Set ${KEY_ENV} or `secrets_key_file` in your config.")
+			},
+		};
+
+		let key = STANDARD
+			.decode(key.trim())
+			.context("The secrets key is not valid base64")?;
+		if key.len() != 32 {
+			bail!("The secrets key must be 32 bytes long. Generate one with `orbit-server secrets keygen`.");
+		}
+
+		Ok(Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+	}
+
+	/// Generate a new random key, encoded as base64.
+	pub fn generate_key() -> String {
+		STANDARD.encode(Aes256Gcm::generate_key(OsRng))
+	}
+
+	pub fn encrypt(&self, name: &str, plaintext: &str) -> Result<String> {
+		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+		let ciphertext = self
+			.0
+			.encrypt(
+				&nonce,
+				Payload {
+					msg: plaintext.as_bytes(),
+					aad: name.as_bytes(),
+				},
+			)
+			.map_err(|_| anyhow::anyhow!("Failed to encrypt secret {name}"))?;
+
+		Ok(format!(
+			"{VERSION_PREFIX}{}",
+			STANDARD.encode([nonce.as_slice(), &ciphertext].concat())
+		))
+	}
+
+	pub fn decrypt(&self, name: &str, encrypted: &str) -> Result<Secret> {
+		let bytes = encrypted
+			.strip_prefix(VERSION_PREFIX)
+			.and_then(|encoded| STANDARD.decode(encoded).ok())
+			.filter(|bytes| bytes.len() > NONCE_LEN)
+			.with_context(|| format!("Secret {name} is not a valid encrypted value"))?;
+
+		let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+		let plaintext = self
+			.0
+			.decrypt(
+				Nonce::from_slice(nonce),
+				Payload {
+					msg: ciphertext,
+					aad: name.as_bytes(),
+				},
+			)
+			.map_err(|_| anyhow::anyhow!("Failed to decrypt secret {name}. Is the key correct?"))?;
+
+		Ok(Secret(
+			String::from_utf8(plaintext).context("Decrypted secret is not valid UTF-8")?,
+		))
+	}
+}
+
+/// The names of the secrets referenced as `${secret:NAME}` in the given text.
+pub fn references(text: &str) -> impl Iterator<Item = &str> {
+	text.split("${secret:")
+		.skip(1)
+		.filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+}
+
+/// Replace every `${secret:NAME}` reference in the given text with the secret's value.
+/// Unknown references are left untouched.
+pub fn expand(text: &str, secrets: &IndexMap<String, Secret>) -> String {
+	secrets
+		.iter()
+		.fold(text.to_string(), |text, (name, secret)| {
+			text.replace(&format!("${{secret:{name}}}"), secret.expose())
+		})
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+	/// Generate a new random key for encrypting secrets.
+	Keygen,
+
+	/// Encrypt a value read from stdin and store it in the config.
+	Encrypt {
+		/// The name of the secret.
+		name: String,
+	},
+
+	/// Print the decrypted value of a secret.
+	Decrypt {
+		/// The name of the secret.
+		name: String,
+	},
+
+	/// Edit all secrets in your $EDITOR, as a decrypted TOML document.
+	Edit,
+}
+
+impl Command {
+	pub fn run(self, config_path: &Path) -> Result<()> {
+		match self {
+			Self::Keygen => println!("{}", Cipher::generate_key()),
+			Self::Decrypt { name } => {
+				let (mut doc, cipher) = load(config_path)?;
+				let Some(encrypted) = secrets_table(&mut doc)?.get(&name).and_then(Item::as_str)
+				else {
+					bail!("Secret {name} does not exist");
+				};
+
+				println!("{}", cipher.decrypt(&name, encrypted)?.expose());
+			},
+			Self::Encrypt { name } => {
+				let (mut doc, cipher) = load(config_path)?;
+
+				let mut plaintext = String::new();
+				io::stdin().read_to_string(&mut plaintext)?;
+
+				let encrypted = cipher.encrypt(&name, plaintext.trim_end_matches(['\r', '\n']))?;
+				secrets_table(&mut doc)?.insert(&name, value(encrypted));
+				fs::write(config_path, doc.to_string())?;
+			},
+			Self::Edit => {
+				let (mut doc, cipher) = load(config_path)?;
+
+				edit(&cipher, secrets_table(&mut doc)?)?;
+				fs::write(config_path, doc.to_string())?;
+			},
+		}
+
+		Ok(())
+	}
+}
+
+/// Read the config as an editable document, along with the cipher for its secrets.
+fn load(config_path: &Path) -> Result<(DocumentMut, Cipher)> {
+	let doc = fs::read_to_string(config_path)
+		.with_context(|| format!("Could not read Orbit config at {}", config_path.display()))?
+		.parse::<DocumentMut>()?;
+
+	// resolved from the config's directory, like when the server loads it
+	let key_file = doc
+		.get("secrets_key_file")
+		.and_then(Item::as_str)
+		.map(|file| {
+			config_path
+				.parent()
+				.map_or_else(|| PathBuf::from(file), |dir| dir.join(file))
+		});
+	let cipher = Cipher::load(key_file.as_deref())?;
+
+	Ok((doc, cipher))
+}
+
+fn secrets_table(doc: &mut DocumentMut) -> Result<&mut dyn TableLike> {
+	doc.entry("secrets")
+		.or_insert_with(toml_edit::table)
+		.as_table_like_mut()
+		.context("`secrets` must be a table")
+}
+
+fn edit(cipher: &Cipher, secrets: &mut dyn TableLike) -> Result<()> {
+	let mut decrypted = IndexMap::new();
+	for (name, encrypted) in secrets.iter() {
+		let encrypted = encrypted
+			.as_str()
+			.with_context(|| format!("Secret {name} is not a string"))?;
+
+		decrypted.insert(name.to_string(), cipher.decrypt(name, encrypted)?);
+	}
+
+	let mut doc = DocumentMut::new();
+	for (name, secret) in &decrypted {
+		doc[name] = value(secret.expose());
+	}
+
+	let path = env::temp_dir().join(format!("orbit-secrets-{}.toml", process::id()));
+	write_private(&path, &doc.to_string())?;
+
+	let edited = run_editor(&path).and_then(|()| Ok(fs::read_to_string(&path)?));
+	fs::remove_file(&path)?;
+	let edited = edited?.parse::<DocumentMut>()?;
+
+	let removed = secrets
+		.iter()
+		.map(|(name, _)| name.to_string())
+		.filter(|name| !edited.contains_key(name))
+		.collect::<Vec<_>>();
+	for name in removed {
+		secrets.remove(&name);
+	}
+
+	for (name, item) in edited.iter() {
+		let plaintext = item
+			.as_str()
+			.with_context(|| format!("Secret {name} must be a string"))?;
+
+		// keep unchanged secrets as they were, to avoid noisy diffs
+		if decrypted
+			.get(name)
+			.is_some_and(|secret| secret.expose() == plaintext)
+		{
+			continue;
+		}
+
+		secrets.insert(name, value(cipher.encrypt(name, plaintext)?));
+	}
+
+	Ok(())
+}
+
+fn run_editor(path: &Path) -> Result<()> {
+	let editor = env::var("VISUAL")
+		.or_else(|_| env::var("EDITOR"))
+		.unwrap_or_else(|_| "vi".to_string());
+	let mut argv = shlex::Shlex::new(&editor);
+
+	let status = process::Command::new(argv.next().context("$EDITOR is empty")?)
+		.args(argv)
+		.arg(path)
+		.status()?;
+
+	if !status.success() {
+		bail!("Editor exited with status {status}, aborting");
+	}
+
+	Ok(())
+}
+
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+	use std::io::Write;
+
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create_new(true);
+
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+	options.open(path)?.write_all(contents.as_bytes())
+}
@@ -1,13 +1,45 @@
 use aide::openapi::{self, OpenApi};
-use anyhow::Result;
-use axum::Extension;
-use std::{env, net::SocketAddr};
-use tokio::{net::TcpListener, signal};
+use anyhow::{Context, Result};
+use axum::{extract::Request, middleware, Extension, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+	rt::{TokioExecutor, TokioIo},
+	server::conn::auto,
+	service::TowerToHyperService,
+};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	signal,
+	sync::watch,
+	time::{sleep, timeout},
+};
+use tower::ServiceExt;
 
-use crate::{config::Config, routes};
+use crate::{
+	access::{Limiter, Peer},
+	audit::AuditLog,
+	config::Config,
+	deploy::Tracker,
+	dotenv::Locks,
+	github::TokenCache,
+	listen::{self, Listener},
+	oidc::Verifier,
+	reload::{self, Reloader},
+	routes,
+	signing::NonceCache,
+	tls,
+};
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait after failing to accept a connection, doubling with each failure in a row
+/// up to `MAX_ACCEPT_BACKOFF`. Errors like running out of file descriptors would otherwise spin the loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 #[allow(clippy::redundant_pub_crate)]
-pub(crate) async fn start(config: Config) -> Result<()> {
+pub(crate) async fn start(path: PathBuf, config: Config) -> Result<()> {
 	let mut openapi = OpenApi {
 		info: openapi::Info {
 			title: "Orbit".to_string(),
@@ -17,25 +49,153 @@ pub(crate) async fn start(config: Config) -> Result<()> {
 		..OpenApi::default()
 	};
 
+	let tls = config
+		.tls
+		.clone()
+		.map(tls::Acceptor::new)
+		.transpose()?
+		.map(Arc::new);
+
+	let config_listen = config.listen.clone();
+	let audit_log = AuditLog::open(&config.audit_log)
+		.with_context(|| format!("Could not open audit log {}", config.audit_log.display()))?;
 	let router = routes::handler().finish_api(&mut openapi);
+	let reloader = Arc::new(Reloader::new(path, config));
 
-	let router = router.layer(config.extension()).layer(Extension(openapi));
+	let router = router
+		.layer(middleware::from_fn(reload::current))
+		.layer(Extension(reloader.clone()))
+		.layer(Extension(openapi))
+		.layer(Extension(Arc::new(Verifier::default())))
+		.layer(Extension(Arc::new(NonceCache::default())))
+		.layer(Extension(Arc::new(Limiter::default())))
+		.layer(Extension(Arc::new(audit_log)))
+		.layer(Extension(Arc::new(Tracker::default())))
+		.layer(Extension(Arc::new(Locks::default())))
+		.layer(Extension(Arc::new(TokenCache::default())));
 
-	let addr = SocketAddr::from((
-		[0, 0, 0, 0],
-		env::var("PORT").map_or(Ok(8000), |p| p.parse())?,
-	));
-	let listener = TcpListener::bind(&addr).await?;
+	let listeners = listen::bind(&config_listen)?;
 
-	tracing::info!("Starting server on {addr}...");
+	tokio::spawn(reloader.watch());
 
-	axum::serve(listener, router.into_make_service())
-		.with_graceful_shutdown(shutdown_signal())
-		.await?;
+	if let Some(tls) = &tls {
+		tokio::spawn(tls.clone().watch());
+	}
+
+	for listener in &listeners {
+		tracing::info!(
+			"Starting server on {}://{listener}...",
+			if tls.is_some() { "https" } else { "http" }
+		);
+	}
+
+	serve(listeners, router, tls).await;
 
 	Ok(())
 }
 
+/// Accept connections until a shutdown signal arrives, then wait for in-flight requests to finish.
+async fn serve(listeners: Vec<Listener>, router: Router, tls: Option<Arc<tls::Acceptor>>) {
+	let (shutdown_tx, shutdown_rx) = watch::channel(());
+	let (close_tx, close_rx) = watch::channel(());
+
+	for listener in listeners {
+		tokio::spawn(accept(
+			listener,
+			router.clone(),
+			tls.clone(),
+			shutdown_rx.clone(),
+			close_rx.clone(),
+		));
+	}
+	drop(close_rx);
+
+	shutdown_signal().await;
+	shutdown_tx.send_replace(());
+
+	close_tx.closed().await;
+}
+
+async fn accept(
+	listener: Listener,
+	router: Router,
+	tls: Option<Arc<tls::Acceptor>>,
+	mut shutdown: watch::Receiver<()>,
+	close_rx: watch::Receiver<()>,
+) {
+	let mut backoff = ACCEPT_BACKOFF;
+
+	loop {
+		let (stream, remote_addr) = tokio::select! {
+			conn = listener.accept() => match conn {
+				Ok(conn) => {
+					backoff = ACCEPT_BACKOFF;
+					conn
+				},
+				Err(e) => {
+					tracing::warn!(e = %e, ?backoff, "Failed to accept connection");
+					tokio::select! {
+						() = sleep(backoff) => {},
+						_ = shutdown.changed() => break,
+					}
+					backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+					continue;
+				},
+			},
+			_ = shutdown.changed() => break,
+		};
+
+		let router = router.clone();
+		let acceptor = tls.as_ref().map(|tls| tls.get());
+		let shutdown = shutdown.clone();
+		let close_rx = close_rx.clone();
+
+		tokio::spawn(async move {
+			match acceptor {
+				None => serve_connection(stream, remote_addr, router, shutdown).await,
+				Some(acceptor) => match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+					Ok(Ok(stream)) => serve_connection(stream, remote_addr, router, shutdown).await,
+					Ok(Err(e)) => tracing::debug!(e = %e, ?remote_addr, "TLS handshake failed"),
+					Err(_) => tracing::debug!(?remote_addr, "TLS handshake timed out"),
+				},
+			}
+
+			drop(close_rx);
+		});
+	}
+}
+
+async fn serve_connection<I>(
+	stream: I,
+	remote_addr: Option<SocketAddr>,
+	router: Router,
+	mut shutdown: watch::Receiver<()>,
+) where
+	I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let service = router.map_request(move |mut request: Request<Incoming>| {
+		request.extensions_mut().insert(Peer(remote_addr));
+		request
+	});
+
+	let builder = auto::Builder::new(TokioExecutor::new());
+	let conn = builder
+		.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+	tokio::pin!(conn);
+
+	let result = tokio::select! {
+		result = conn.as_mut() => result,
+		_ = shutdown.changed() => {
+			conn.as_mut().graceful_shutdown();
+			conn.await
+		},
+	};
+
+	if let Err(e) = result {
+		tracing::debug!(e = %e, "Connection closed with an error");
+	}
+}
+
 async fn shutdown_signal() {
 	let ctrl_c = async {
 		signal::ctrl_c()
@@ -0,0 +1,179 @@
+use async_fn_stream::try_fn_stream;
+use axum::{
+	body::{self, Body},
+	extract::Request,
+	middleware::Next,
+	response::Response,
+	Extension,
+};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, StatusCode};
+use orbit_types::{
+	signing::{self, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+	CONTENT_SHA256_HEADER,
+};
+use sha2::{Digest, Sha256};
+use std::{
+	collections::HashMap,
+	io,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::{Config, Signing};
+
+/// The largest body we're willing to buffer to verify its signature.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Remembers the nonces of recently verified requests, so they can't be replayed.
+#[derive(Debug, Default)]
+pub struct NonceCache(Mutex<HashMap<String, Instant>>);
+
+impl NonceCache {
+	/// Record a nonce, returning false if it was already used. Nonces are forgotten once
+	/// they're old enough that their request would be rejected for its timestamp anyway.
+	fn insert(&self, nonce: &str, ttl: Duration) -> bool {
+		let mut nonces = self.0.lock().unwrap();
+
+		nonces.retain(|_, seen_at| seen_at.elapsed() < ttl);
+		nonces.insert(nonce.to_string(), Instant::now()).is_none()
+	}
+}
+
+/// Verify the signature of requests, if the server is configured to check them.
+pub async fn verify(
+	Extension(config): Extension<Arc<Config>>,
+	Extension(nonces): Extension<Arc<NonceCache>>,
+	request: Request,
+	next: Next,
+) -> Result<Response, StatusCode> {
+	let Some(signing) = &config.signing else {
+		return Ok(next.run(request).await);
+	};
+
+	if !request.headers().contains_key(SIGNATURE_HEADER) {
+		if signing.required {
+			tracing::warn!(path = %request.uri().path(), "Rejected unsigned request");
+			return Err(StatusCode::UNAUTHORIZED);
+		}
+
+		return Ok(next.run(request).await);
+	}
+
+	let (parts, body) = request.into_parts();
+
+	// streamed bodies (like artifacts) can be too large to buffer, so the signature covers
+	// their declared hash instead, and the body is checked against it as it's read
+	let (body, body_hash) = if let Some(declared) = parts.headers.get(CONTENT_SHA256_HEADER) {
+		let body_hash = declared
+			.to_str()
+			.ok()
+			.filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+			.ok_or(StatusCode::BAD_REQUEST)?
+			.to_lowercase();
+
+		(verified(body, body_hash.clone()), body_hash)
+	} else {
+		let body = body::to_bytes(body, MAX_BODY_SIZE)
+			.await
+			.map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+		let body_hash = format!("{:x}", Sha256::digest(&body));
+
+		(Body::from(body), body_hash)
+	};
+
+	let path_and_query = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+
+	if let Err(reason) = check(
+		signing,
+		&nonces,
+		&parts.headers,
+		parts.method.as_str(),
+		path_and_query,
+		&body_hash,
+	) {
+		tracing::warn!(path = %parts.uri.path(), reason, "Rejected signed request");
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+
+	Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+/// Pass the body through, failing once it's been read if it doesn't match the expected hash.
+fn verified(body: Body, expected: String) -> Body {
+	let mut chunks = body.into_data_stream();
+
+	Body::from_stream(try_fn_stream(|stream| async move {
+		let mut hasher = Sha256::new();
+
+		while let Some(chunk) = chunks.next().await {
+			let chunk = chunk.map_err(io::Error::other)?;
+			hasher.update(&chunk);
+			stream.emit(chunk).await;
+		}
+
+		if format!("{:x}", hasher.finalize()) != expected {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidData,
+				"the body doesn't match its signed hash",
+			));
+		}
+
+		Ok(())
+	}))
+}
+
+fn check(
+	signing: &Signing,
+	nonces: &NonceCache,
+	headers: &HeaderMap,
+	method: &str,
+	path_and_query: &str,
+	body_hash: &str,
+) -> Result<(), &'static str> {
+	let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+	let timestamp = header(TIMESTAMP_HEADER)
+		.and_then(|timestamp| timestamp.parse::<u64>().ok())
+		.ok_or("missing or invalid timestamp")?;
+	let nonce = header(NONCE_HEADER)
+		.filter(|nonce| !nonce.is_empty() && nonce.len() <= 128)
+		.ok_or("missing or invalid nonce")?;
+	let signature = header(SIGNATURE_HEADER)
+		.and_then(|signature| signature.strip_prefix("v1="))
+		.and_then(decode_hex)
+		.ok_or("missing or invalid signature")?;
+
+	let now = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_secs();
+	if now.abs_diff(timestamp) > signing.max_skew {
+		return Err("timestamp outside the allowed clock skew");
+	}
+
+	let mut mac = Hmac::<Sha256>::new_from_slice(signing.key.as_bytes())
+		.map_err(|_| "invalid signing key")?;
+	mac.update(signing::payload(timestamp, nonce, method, path_and_query, body_hash).as_bytes());
+	mac.verify_slice(&signature)
+		.map_err(|_| "signature mismatch")?;
+
+	// only remember nonces of valid requests, so forged ones can't fill up the cache
+	if !nonces.insert(nonce, Duration::from_secs(signing.max_skew * 2)) {
+		return Err("nonce has already been used");
+	}
+
+	Ok(())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		return None;
+	}
+
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+		.collect()
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Context, Result};
+use std::{
+	fs::{self, File},
+	io::BufReader,
+	path::Path,
+	sync::{Arc, RwLock},
+	time::{Duration, SystemTime},
+};
+use tokio_rustls::{
+	rustls::{crypto::ring, server::WebPkiClientVerifier, RootCertStore, ServerConfig},
+	TlsAcceptor,
+};
+
+use crate::config::Tls;
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Accepts TLS connections, picking up new certificates when their files change.
+pub struct Acceptor {
+	config: Tls,
+	current: RwLock<TlsAcceptor>,
+}
+
+impl Acceptor {
+	pub fn new(config: Tls) -> Result<Self> {
+		let acceptor = load(&config)?;
+
+		Ok(Self {
+			config,
+			current: RwLock::new(acceptor),
+		})
+	}
+
+	/// The acceptor for the current certificates.
+	pub fn get(&self) -> TlsAcceptor {
+		self.current.read().unwrap().clone()
+	}
+
+	/// Reload the certificates whenever their files change. If the new files are invalid,
+	/// the error is logged and the previous certificates keep being served.
+	pub async fn watch(self: Arc<Self>) {
+		let mut modified = self.modified();
+		let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+		loop {
+			interval.tick().await;
+
+			let current = self.modified();
+			if current == modified {
+				continue;
+			}
+			modified = current;
+
+			match load(&self.config) {
+				Ok(acceptor) => {
+					*self.current.write().unwrap() = acceptor;
+					tracing::info!("Reloaded TLS certificates");
+				},
+				Err(e) => tracing::error!("Failed to reload TLS certificates: {e:#}"),
+			}
+		}
+	}
+
+	fn modified(&self) -> Vec<Option<SystemTime>> {
+		[
+			Some(&self.config.cert),
+			Some(&self.config.key),
+			self.config.client_ca.as_ref(),
+		]
+		.into_iter()
+		.flatten()
+		.map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+		.collect()
+	}
+}
+
+fn load(config: &Tls) -> Result<TlsAcceptor> {
+	let provider = Arc::new(ring::default_provider());
+
+	let certs = rustls_pemfile::certs(&mut open(&config.cert)?)
+		.collect::<Result<Vec<_>, _>>()
+		.with_context(|| format!("Invalid TLS certificate {}", config.cert.display()))?;
+	if certs.is_empty() {
+		bail!("No certificates found in {}", config.cert.display());
+	}
+
+	let key = rustls_pemfile::private_key(&mut open(&config.key)?)
+		.with_context(|| format!("Invalid TLS key {}", config.key.display()))?
+		.with_context(|| format!("No private key found in {}", config.key.display()))?;
+
+	let builder = ServerConfig::builder_with_provider(provider.clone())
+		.with_safe_default_protocol_versions()?;
+
+	let builder = if let Some(path) = &config.client_ca {
+		let mut roots = RootCertStore::empty();
+		for cert in rustls_pemfile::certs(&mut open(path)?) {
+			roots
+				.add(cert?)
+				.with_context(|| format!("Invalid client CA certificate in {}", path.display()))?;
+		}
+
+		builder.with_client_cert_verifier(
+			WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?,
+		)
+	} else {
+		builder.with_no_client_auth()
+	};
+
+	let mut server_config = builder
+		.with_single_cert(certs, key)
+		.context("TLS certificate doesn't match its key")?;
+	server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+	Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+	let file = File::open(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+	Ok(BufReader::new(file))
+}
@@ -1,15 +1,28 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// A progress update for a deployment.
 pub enum Progress {
 	Log(Log),
 	Stage(Stage),
+	Download(DownloadProgress),
+}
+
+/// How much of the repository has been downloaded.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DownloadProgress {
+	/// The bytes downloaded so far.
+	pub downloaded: u64,
+	/// The size of the download, if the server reported it.
+	pub total: Option<u64>,
 }
 
 /// A log message.
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[serde(tag = "type", content = "log")]
 pub enum Log {
 	Info(String),
@@ -71,6 +84,18 @@ pub enum Error {
 	/// Failed to build the deployment.
 	#[error("Failed to publish the new deployment.")]
 	Publish,
+
+	/// Failed to receive the uploaded artifact.
+	#[error("Failed to receive the artifact.")]
+	Upload,
+
+	/// The artifact didn't match its checksum, or had none to verify it against.
+	#[error("Failed to verify the artifact's checksum.")]
+	Checksum,
+
+	/// The release asset or workflow artifact to deploy doesn't exist.
+	#[error("Failed to find the build to deploy.")]
+	MissingBuild,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -93,3 +118,145 @@ impl From<Stage> for Progress {
 		Self::Stage(value)
 	}
 }
+
+/// Changes to apply to a site's `.env` file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct EnvUpdate {
+	/// Variables to add or overwrite.
+	#[serde(default)]
+	pub set: BTreeMap<String, String>,
+	/// Variables to remove.
+	#[serde(default)]
+	pub unset: Vec<String>,
+}
+
+/// The contents of a site's `.env` file.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct EnvResponse {
+	/// The variables in the file, with their values masked.
+	pub env: BTreeMap<String, String>,
+	/// The output of `php artisan config:cache`, if it was requested.
+	pub config_cache: Option<CommandOutput>,
+}
+
+/// The output of a command run on the server.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CommandOutput {
+	/// Whether the command exited successfully.
+	pub success: bool,
+	/// The lines the command printed.
+	pub logs: Vec<Log>,
+}
+
+/// A site the token can access.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SiteSummary {
+	/// The slug the site is referred to by in the API.
+	pub slug: String,
+	/// The name of the site.
+	pub name: String,
+	/// The GitHub repository the site is deployed from.
+	pub github_repo: String,
+	/// The id of the deployment that's currently live, if any.
+	pub live: Option<String>,
+	/// The commit the live deployment was built from, if known.
+	pub commit: Option<String>,
+	/// When the live deployment went live, if known.
+	pub deployed_at: Option<DateTime<Utc>>,
+}
+
+/// What's deployed on a site.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SiteStatus {
+	/// The name of the site.
+	pub name: String,
+	/// The slug the site is referred to by in the API.
+	pub slug: String,
+	/// The release that's currently live, if any.
+	pub live: Option<Release>,
+	/// Every release on disk, newest first.
+	pub releases: Vec<Release>,
+	/// The deployments currently in progress.
+	pub running: Vec<RunningDeployment>,
+	/// The most recent deployment that failed, if any.
+	pub last_failure: Option<DeploymentFailure>,
+}
+
+/// A deployment on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Release {
+	/// The id of the deployment that created the release.
+	pub id: String,
+	/// The git ref that was requested, if any.
+	pub r#ref: Option<String>,
+	/// The commit the release was built from. Unknown for releases created by older versions of Orbit.
+	pub commit: Option<String>,
+	/// The SHA-256 checksum of the artifact the release was made from, if it wasn't built from the repository's source.
+	pub artifact: Option<String>,
+	/// When the release went live, if it ever did.
+	pub deployed_at: Option<DateTime<Utc>>,
+	/// The size of the release on disk, in bytes.
+	pub size: u64,
+}
+
+/// A deployment in progress.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RunningDeployment {
+	/// The id of the deployment.
+	pub id: String,
+	/// The git ref being deployed, if any.
+	pub r#ref: Option<String>,
+	/// The name of the token that started the deployment.
+	pub triggered_by: String,
+	/// When the deployment started.
+	pub started_at: DateTime<Utc>,
+}
+
+/// A deployment that failed.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DeploymentFailure {
+	/// The id of the deployment.
+	pub id: String,
+	/// The git ref that was being deployed, if any.
+	pub r#ref: Option<String>,
+	/// The name of the token that started the deployment.
+	pub triggered_by: String,
+	/// When the deployment failed.
+	pub failed_at: DateTime<Utc>,
+	/// What went wrong.
+	pub error: String,
+}
+
+/// The hex-encoded SHA-256 of a request body that's streamed rather than buffered, like an
+/// uploaded artifact. The server checks the body against it, and signatures cover it instead of the body.
+pub const CONTENT_SHA256_HEADER: &str = "x-orbit-content-sha256";
+
+/// Headers used to sign API requests with a shared key.
+pub mod signing {
+	/// The unix timestamp (in seconds) the request was signed at.
+	pub const TIMESTAMP_HEADER: &str = "x-orbit-timestamp";
+	/// A random value, unique to each request.
+	pub const NONCE_HEADER: &str = "x-orbit-nonce";
+	/// The hex-encoded HMAC-SHA256 of the [`payload`], prefixed with `v1=`.
+	pub const SIGNATURE_HEADER: &str = "x-orbit-signature";
+
+	/// The string that gets signed for a request. `body_hash` is the hex-encoded SHA-256 of the request body.
+	#[must_use]
+	pub fn payload(
+		timestamp: u64,
+		nonce: &str,
+		method: &str,
+		path_and_query: &str,
+		body_hash: &str,
+	) -> String {
+		format!("v1\n{timestamp}\n{nonce}\n{method}\n{path_and_query}\n{body_hash}")
+	}
+}